@@ -2,6 +2,8 @@ use clap::Parser;
 use directories::UserDirs;
 use std::path::PathBuf;
 
+use crate::vm::{DEFAULT_GC_SLICE_BUDGET, DEFAULT_MAX_FRAMES, DEFAULT_MAX_STACK};
+
 #[derive(Debug, Parser)]
 #[command(version, about)]
 pub struct Args {
@@ -18,6 +20,34 @@ pub struct Args {
     /// Garbage collection logs
     #[clap(short, long, default_value_t)]
     pub gc_log: bool,
+
+    /// Maximum depth of the call stack
+    #[clap(long, default_value_t = DEFAULT_MAX_FRAMES)]
+    pub max_frames: usize,
+
+    /// Maximum number of values on the value stack
+    #[clap(long, default_value_t = DEFAULT_MAX_STACK)]
+    pub max_stack: usize,
+
+    /// Objects marked or swept per allocation during incremental garbage collection
+    #[clap(long, default_value_t = DEFAULT_GC_SLICE_BUDGET)]
+    pub gc_slice_budget: usize,
+
+    /// Maximum heap size in bytes, exceeding it is a runtime error
+    #[clap(long)]
+    pub max_heap_size: Option<usize>,
+
+    /// Maximum number of instructions executed per script or REPL line
+    #[clap(long)]
+    pub max_instructions: Option<u64>,
+
+    /// Maximum wall-clock time in milliseconds per script or REPL line
+    #[clap(long)]
+    pub timeout: Option<u64>,
+
+    /// Allow scripts to open, read and write files
+    #[clap(long, default_value_t)]
+    pub allow_file_access: bool,
 }
 
 fn get_default_history_file() -> PathBuf {
@@ -1,5 +1,6 @@
 use miette::{NamedSource, SourceCode, SourceSpan};
 use std::{
+    cell::Cell,
     fmt::{Debug, Error, Write as _},
     ops::Deref,
     sync::Arc,
@@ -7,15 +8,29 @@ use std::{
 
 use crate::{
     datastructures::vector::LoxVector,
+    inline_cache::InlineCache,
     op::Op,
     types::{obj::Obj, value::Value},
 };
 
+/// Catches exceptions raised by the instructions in `start..end` by jumping to `target`,
+/// after the stack was cut back to `stack_depth` values above the frame's slots.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExceptionHandler {
+    pub start: usize,
+    pub end: usize,
+    pub target: usize,
+    pub stack_depth: usize,
+}
+
 pub struct Chunk {
     // in original clox this is Vector<u8> this is more wasteful but way easier. Maybe benchmark in the future?
     pub code: LoxVector<Op>,
     pub constants: LoxVector<Value>,
     pub locations: LoxVector<SourceSpan>,
+    pub caches: LoxVector<Cell<InlineCache>>,
+    // inner handlers come before the handlers of enclosing try statements
+    pub handlers: LoxVector<ExceptionHandler>,
     pub source: Arc<NamedSource<String>>,
 }
 
@@ -25,6 +40,8 @@ impl Chunk {
             code: LoxVector::new(),
             constants: LoxVector::new(),
             locations: LoxVector::new(),
+            caches: LoxVector::new(),
+            handlers: LoxVector::new(),
             source,
         }
     }
@@ -34,6 +51,11 @@ impl Chunk {
         self.locations.push(location);
     }
 
+    pub fn pop(&mut self) -> Option<Op> {
+        self.locations.pop();
+        self.code.pop()
+    }
+
     pub fn add_constant(&mut self, value: Value) -> u8 {
         self.constants.push(value);
         (self.constants.len() - 1)
@@ -41,6 +63,33 @@ impl Chunk {
             .expect("constant count overflows u8, not supported")
     }
 
+    pub fn add_cache(&mut self) -> u16 {
+        self.caches.push(Cell::new(InlineCache::new()));
+        (self.caches.len() - 1)
+            .try_into()
+            .expect("inline cache count overflows u16, not supported")
+    }
+
+    pub fn allocated_bytes(&self) -> usize {
+        self.code.allocated_bytes()
+            + self.constants.allocated_bytes()
+            + self.locations.allocated_bytes()
+            + self.caches.allocated_bytes()
+            + self.handlers.allocated_bytes()
+    }
+
+    pub fn add_handler(&mut self, handler: ExceptionHandler) {
+        self.handlers.push(handler);
+    }
+
+    /// The innermost handler covering the instruction at `at`.
+    pub fn find_handler(&self, at: usize) -> Option<ExceptionHandler> {
+        self.handlers
+            .iter()
+            .find(|handler| (handler.start..handler.end).contains(&at))
+            .copied()
+    }
+
     pub fn disassemble(&self) -> String {
         let mut result = String::new();
         let _ = writeln!(&mut result, "== {} ==", self.source.name());
@@ -54,6 +103,13 @@ impl Chunk {
             let _ = writeln!(&mut result, "{disassembled}");
             last_line_number = Some(line_number);
         }
+        for handler in self.handlers.iter() {
+            let _ = writeln!(
+                &mut result,
+                "handler {:0>4}..{:0>4} -> {:0>4} depth {}",
+                handler.start, handler.end, handler.target, handler.stack_depth
+            );
+        }
         result
     }
 
@@ -109,9 +165,17 @@ impl Chunk {
             | Op::SetGlobal(idx)
             | Op::Class(idx)
             | Op::Method(idx)
-            | Op::GetProperty(idx)
-            | Op::SetProperty(idx)
-            | Op::GetSuper(idx) => {
+            | Op::StaticMethod(idx)
+            | Op::GetProperty {
+                property_index: idx,
+                ..
+            }
+            | Op::SetProperty {
+                property_index: idx,
+                ..
+            }
+            | Op::GetSuper(idx)
+            | Op::Import(idx) => {
                 let const_index: usize = (*idx).into();
                 let constant = self.constants[const_index];
                 write!(&mut result, "{:<16} {:<4} '{}'", op, const_index, constant)?;
@@ -120,8 +184,32 @@ impl Chunk {
             | Op::SetLocal(byte)
             | Op::GetUpvalue(byte)
             | Op::SetUpvalue(byte)
-            | Op::Call(byte) => write!(&mut result, "{:<16} {:<4}", op, byte)?,
-            Op::JumpIfFalse(jump) | Op::Jump(jump) => write!(
+            | Op::Call(byte)
+            | Op::TailCall(byte)
+            | Op::BuildString(byte)
+            | Op::Dup(byte)
+            | Op::Bury(byte)
+            | Op::EndFinally(byte) => write!(&mut result, "{:<16} {:<4}", op, byte)?,
+            Op::AddLocals { left, right } => {
+                write!(&mut result, "{:<16} {:<4} {:<4}", op, left, right)?
+            }
+            Op::JumpIfLocalNotLess {
+                slot,
+                constant,
+                offset: jump,
+            } => {
+                let const_index: usize = (*constant).into();
+                write!(
+                    &mut result,
+                    "{:<16} {:<4} < '{}' {:0>4} -> {:0>4}",
+                    op,
+                    slot,
+                    self.constants[const_index],
+                    offset,
+                    offset + (*jump as usize)
+                )?
+            }
+            Op::JumpIfFalse(jump) | Op::JumpIfNil(jump) | Op::Jump(jump) => write!(
                 &mut result,
                 "{:<16} {:0>4} -> {:0>4}",
                 op,
@@ -156,6 +244,7 @@ impl Chunk {
             Op::Invoke {
                 property_index,
                 arg_count,
+                ..
             }
             | Op::SuperInvoke {
                 property_index,
@@ -208,4 +297,25 @@ mod tests {
         let res = chunk.disassemble_at(0);
         assert_eq!(res, "0000    1 CONSTANT         0    '1.1'");
     }
+
+    #[test]
+    fn disassemble_superinstruction() {
+        let src = "a < 10".to_string();
+        let src = Arc::new(NamedSource::new("src", src));
+        let mut chunk = Chunk::new(src);
+        let constant = chunk.add_constant(Value::Number(10.0));
+        chunk.write(
+            Op::JumpIfLocalNotLess {
+                slot: 1,
+                constant,
+                offset: 3,
+            },
+            SourceSpan::from((2, 1)),
+        );
+        let res = chunk.disassemble_at(0);
+        assert_eq!(
+            res,
+            "0000    1 JUMP_IF_LOCAL_NOT_LESS 1    < '10' 0000 -> 0003"
+        );
+    }
 }
@@ -30,18 +30,31 @@ pub struct Compiler<'a> {
     locals: Vec<Local<'a>>,
     pub upvalues: Vec<UpvalueIndex>,
     scope_depth: u32,
+    // offset of the last instruction a jump lands on, instructions before it must not be fused
+    jump_target: usize,
+    // try statements around the code being compiled, innermost last
+    pub tries: Vec<TryContext>,
     pub chunk: Chunk,
 }
 
+/// A try statement of the function being compiled. Its finally block is entered by jumps,
+/// two hidden locals starting at `kind_slot` remember how: nil when the try or catch block
+/// finished, true with an exception to rethrow or false with a value to return.
+pub struct TryContext {
+    pub kind_slot: u8,
+    pub stack_depth: usize,
+    pub finally_jumps: Vec<Jump>,
+}
+
 #[derive(PartialEq, Debug)]
 pub struct ResolveResult {
     pub slot: u8,
     pub initialized: bool,
 }
 pub struct Jump {
-    op: fn(u16) -> Op,
     location: SourceSpan,
     position: usize,
+    condition_on_stack: bool,
 }
 
 impl<'a> Compiler<'a> {
@@ -71,6 +84,8 @@ impl<'a> Compiler<'a> {
             locals: vec![slot_zero],
             upvalues: vec![],
             scope_depth: 0,
+            jump_target: 0,
+            tries: vec![],
             chunk: Chunk::new(src),
         }
     }
@@ -100,6 +115,21 @@ impl<'a> Compiler<'a> {
         }
     }
 
+    pub fn local_count(&self) -> usize {
+        self.locals.len()
+    }
+
+    // pops the locals above stack_depth for a jump out of their scopes, they stay declared
+    pub fn emit_discard_locals(&mut self, stack_depth: usize, location: SourceSpan) {
+        for index in (stack_depth..self.locals.len()).rev() {
+            if self.locals[index].is_captured {
+                self.chunk.write(Op::CloseUpvalue, location);
+            } else {
+                self.chunk.write(Op::Pop, location);
+            }
+        }
+    }
+
     pub fn add_local(&mut self, name: &'a str, location: SourceSpan) -> Result<()> {
         if self.locals.len() > u8::MAX as usize {
             miette::bail!(
@@ -157,6 +187,24 @@ impl<'a> Compiler<'a> {
         None
     }
 
+    // `this` is a local or captured like one, but can't be assigned
+    pub fn reads_this(&self, op: &Op) -> bool {
+        match op {
+            Op::GetLocal(slot) => self.locals[*slot as usize].name == "this",
+            Op::GetUpvalue(index) => {
+                let upvalue = &self.upvalues[*index as usize];
+                self.enclosing.as_ref().is_some_and(|enclosing| {
+                    if upvalue.is_local() {
+                        enclosing.reads_this(&Op::GetLocal(upvalue.index()))
+                    } else {
+                        enclosing.reads_this(&Op::GetUpvalue(upvalue.index()))
+                    }
+                })
+            }
+            _ => false,
+        }
+    }
+
     fn add_upvalue(&mut self, index: u8, is_local: bool) -> u8 {
         let upvalue = UpvalueIndex::new(index, is_local);
         if let Some(i) = self.upvalues.iter().position(|u| u == &upvalue) {
@@ -198,13 +246,70 @@ impl<'a> Compiler<'a> {
         self.chunk.write(Op::Constant(idx), location);
     }
 
+    pub fn emit_binary(&mut self, op: Op, location: SourceSpan) {
+        if op == Op::Add {
+            if let Some(&[Op::GetLocal(left), Op::GetLocal(right)]) = self.fusable_tail(2) {
+                self.fuse(2, Op::AddLocals { left, right }, location);
+                return;
+            }
+        }
+        self.chunk.write(op, location);
+    }
+
     pub fn emit_jump(&mut self, op: fn(u16) -> Op, location: SourceSpan) -> Jump {
         let position = self.chunk.code.len();
         self.chunk.write(op(0), location);
         Jump {
-            op,
             location,
             position,
+            condition_on_stack: true,
+        }
+    }
+
+    // emits the jump of a statement condition and pops the condition on the fall-through path
+    pub fn emit_condition_jump(&mut self, location: SourceSpan) -> Jump {
+        if let Some(&[Op::GetLocal(slot), Op::Constant(constant), Op::Less]) = self.fusable_tail(3)
+        {
+            let less_location = *self
+                .chunk
+                .locations
+                .last()
+                .expect("checked by fusable_tail");
+            let position = self.chunk.code.len() - 3;
+            let op = Op::JumpIfLocalNotLess {
+                slot,
+                constant,
+                offset: 0,
+            };
+            self.fuse(3, op, less_location);
+            Jump {
+                location,
+                position,
+                condition_on_stack: false,
+            }
+        } else {
+            let jump = self.emit_jump(Op::JumpIfFalse, location);
+            self.chunk.write(Op::Pop, location);
+            jump
+        }
+    }
+
+    pub fn loop_start(&mut self) -> usize {
+        self.jump_target = self.chunk.code.len();
+        self.jump_target
+    }
+
+    // entered by the VM when an exception is caught, instructions before it must not be fused
+    pub fn handler_target(&mut self) -> usize {
+        self.jump_target = self.chunk.code.len();
+        self.jump_target
+    }
+
+    // a call directly before a return can reuse the frame of the returning function
+    pub fn mark_tail_call(&mut self) {
+        let last = self.chunk.code.len().saturating_sub(1);
+        if let Some(&Op::Call(arg_count)) = self.chunk.code.last() {
+            self.chunk.code[last] = Op::TailCall(arg_count);
         }
     }
 
@@ -223,8 +328,10 @@ impl<'a> Compiler<'a> {
 
     pub fn patch_jump(&mut self, jump: Jump) -> Result<()> {
         let jump_length = self.chunk.code.len() - jump.position;
+        self.jump_target = self.chunk.code.len();
         if let Ok(jump_length) = u16::try_from(jump_length) {
-            self.chunk.code[jump.position] = (jump.op)(jump_length);
+            self.chunk.code[jump.position] =
+                self.chunk.code[jump.position].with_jump_offset(jump_length);
             Ok(())
         } else {
             miette::bail!(
@@ -233,6 +340,46 @@ impl<'a> Compiler<'a> {
             )
         }
     }
+
+    // patches a jump from emit_condition_jump and pops the condition on the jump target
+    pub fn patch_condition_jump(&mut self, jump: Jump) -> Result<()> {
+        let location = jump.location;
+        let condition_on_stack = jump.condition_on_stack;
+        self.patch_jump(jump)?;
+        if condition_on_stack {
+            self.chunk.write(Op::Pop, location);
+        }
+        Ok(())
+    }
+
+    // the last `count` instructions, if no jump lands in between them
+    fn fusable_tail(&self, count: usize) -> Option<&[Op]> {
+        let start = self.chunk.code.len().checked_sub(count)?;
+        if self.jump_target > start {
+            None
+        } else {
+            Some(&self.chunk.code[start..])
+        }
+    }
+
+    // removes the last op if it reads a variable, property or index, so it can be updated instead
+    pub fn pop_read(&mut self) -> Option<Op> {
+        match self.fusable_tail(1)? {
+            [Op::GetLocal(_)
+            | Op::GetUpvalue(_)
+            | Op::GetGlobal(_)
+            | Op::GetProperty { .. }
+            | Op::Index] => self.chunk.pop(),
+            _ => None,
+        }
+    }
+
+    fn fuse(&mut self, count: usize, op: Op, location: SourceSpan) {
+        for _ in 0..count {
+            self.chunk.pop();
+        }
+        self.chunk.write(op, location);
+    }
 }
 
 #[cfg(test)]
@@ -283,8 +430,10 @@ mod tests {
             ],
             upvalues: vec![],
             scope_depth: 2,
+            jump_target: 0,
             function_type: FunctionType::Script,
             function_name: None,
+            tries: vec![],
             chunk: Chunk::new(empty_src()),
         };
         assert!(!compiler.has_variable_in_current_scope("a"));
@@ -309,8 +458,10 @@ mod tests {
             ],
             upvalues: vec![],
             scope_depth: 2,
+            jump_target: 0,
             function_type: FunctionType::Script,
             function_name: None,
+            tries: vec![],
             chunk: Chunk::new(empty_src()),
         };
         assert!(compiler.has_variable_in_current_scope("a"));
@@ -340,8 +491,10 @@ mod tests {
             ],
             upvalues: vec![],
             scope_depth: 2,
+            jump_target: 0,
             function_type: FunctionType::Script,
             function_name: None,
+            tries: vec![],
             chunk: Chunk::new(empty_src()),
         };
         assert!(compiler.has_variable_in_current_scope("b"));
@@ -372,8 +525,10 @@ mod tests {
             ],
             upvalues: vec![],
             scope_depth: 2,
+            jump_target: 0,
             function_type: FunctionType::Script,
             function_name: None,
+            tries: vec![],
             chunk: Chunk::new(empty_src()),
         };
         compiler.end_scope(location);
@@ -406,8 +561,10 @@ mod tests {
             ],
             upvalues: vec![],
             scope_depth: 2,
+            jump_target: 0,
             function_type: FunctionType::Script,
             function_name: None,
+            tries: vec![],
             chunk: Chunk::new(empty_src()),
         };
         assert_eq!(
@@ -443,8 +600,10 @@ mod tests {
             ],
             upvalues: vec![],
             scope_depth: 2,
+            jump_target: 0,
             function_type: FunctionType::Script,
             function_name: None,
+            tries: vec![],
             chunk: Chunk::new(empty_src()),
         };
         assert_eq!(
@@ -467,4 +626,67 @@ mod tests {
             vec![UpvalueIndex::new(4, true), UpvalueIndex::new(2, false)]
         );
     }
+
+    #[test]
+    fn fuse_add_locals() {
+        let location = SourceSpan::from((0, 0));
+        let add_location = SourceSpan::from((2, 1));
+        let mut compiler = Compiler::new(FunctionType::Function, None, empty_src());
+        compiler.chunk.write(Op::GetLocal(1), location);
+        compiler.chunk.write(Op::GetLocal(2), location);
+        compiler.emit_binary(Op::Add, add_location);
+        assert_eq!(compiler.chunk.code.len(), 1);
+        assert_eq!(compiler.chunk.code[0], Op::AddLocals { left: 1, right: 2 });
+        assert_eq!(compiler.chunk.locations[0], add_location);
+    }
+
+    #[test]
+    fn do_not_fuse_over_jump_target() {
+        let location = SourceSpan::from((0, 0));
+        let mut compiler = Compiler::new(FunctionType::Function, None, empty_src());
+        compiler.chunk.write(Op::GetLocal(1), location);
+        let jump = compiler.emit_jump(Op::JumpIfFalse, location);
+        compiler.chunk.write(Op::Pop, location);
+        compiler.chunk.write(Op::GetLocal(2), location);
+        compiler.patch_jump(jump).unwrap();
+        compiler.chunk.write(Op::GetLocal(3), location);
+        compiler.emit_binary(Op::Add, location);
+        assert_eq!(compiler.chunk.code.len(), 6);
+        assert_eq!(compiler.chunk.code[5], Op::Add);
+    }
+
+    #[test]
+    fn mark_tail_call() {
+        let location = SourceSpan::from((0, 0));
+        let mut compiler = Compiler::new(FunctionType::Function, None, empty_src());
+        compiler.chunk.write(Op::GetGlobal(0), location);
+        compiler.mark_tail_call();
+        assert_eq!(compiler.chunk.code[0], Op::GetGlobal(0));
+        compiler.chunk.write(Op::Call(2), location);
+        compiler.mark_tail_call();
+        assert_eq!(compiler.chunk.code[1], Op::TailCall(2));
+    }
+
+    #[test]
+    fn fuse_condition_jump() {
+        let location = SourceSpan::from((0, 0));
+        let less_location = SourceSpan::from((2, 1));
+        let mut compiler = Compiler::new(FunctionType::Function, None, empty_src());
+        compiler.chunk.write(Op::GetLocal(1), location);
+        compiler.emit_constant(Value::Number(10.0), location);
+        compiler.emit_binary(Op::Less, less_location);
+        let jump = compiler.emit_condition_jump(location);
+        compiler.chunk.write(Op::Nil, location);
+        compiler.patch_condition_jump(jump).unwrap();
+        assert_eq!(compiler.chunk.code.len(), 2);
+        assert_eq!(
+            compiler.chunk.code[0],
+            Op::JumpIfLocalNotLess {
+                slot: 1,
+                constant: 0,
+                offset: 2
+            }
+        );
+        assert_eq!(compiler.chunk.locations[0], less_location);
+    }
 }
@@ -32,7 +32,7 @@ impl HashTable {
     }
 
     pub fn insert(&mut self, key: Value, value: Value) -> bool {
-        if (self.count + 1) as f32 > MAX_LOAD * self.capacity as f32 {
+        if self.is_full() {
             let new_capacity: u32 = u32::try_from(memory::grow_capacity(self.capacity as usize))
                 .expect("max capacity is u32");
             self.adjust_capacity(new_capacity);
@@ -171,6 +171,24 @@ impl HashTable {
         }
     }
 
+    pub fn allocated_bytes(&self) -> usize {
+        self.capacity as usize * std::mem::size_of::<Entry>()
+    }
+
+    /// Bytes the next insert allocates, also if the key exists already.
+    pub fn growth(&self) -> usize {
+        if self.is_full() {
+            let capacity = self.capacity as usize;
+            (memory::grow_capacity(capacity) - capacity) * std::mem::size_of::<Entry>()
+        } else {
+            0
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        (self.count + 1) as f32 > MAX_LOAD * self.capacity as f32
+    }
+
     pub fn mark(&self, gc: &mut Gc) {
         for i in 0..self.capacity {
             let mut entry = unsafe { *self.entries.as_ptr().add(i as usize) };
@@ -181,11 +199,20 @@ impl HashTable {
         }
     }
 
-    pub fn remove_white(&mut self) {
+    pub fn entries(&self) -> impl Iterator<Item = (Value, Value)> + '_ {
+        (0..self.capacity).filter_map(|i| {
+            let entry = unsafe { *self.entries.as_ptr().add(i as usize) };
+            entry.key.map(|key| (key, entry.value))
+        })
+    }
+
+    // a young collection only marks young objects, old keys are kept
+    pub fn remove_white(&mut self, young_only: bool) {
         for i in 0..self.capacity {
             let entry = unsafe { *self.entries.as_ptr().add(i as usize) };
             if let Some(mut key) = entry.key {
-                if !key.is_marked() {
+                let kept_old = young_only && matches!(key, Value::Obj(obj) if obj.is_old());
+                if !kept_old && !key.is_marked() {
                     self.delete(key);
                 }
             }
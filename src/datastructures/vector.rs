@@ -46,9 +46,31 @@ impl<T> LoxVector<T> {
         self.count += 1;
     }
 
+    pub fn pop(&mut self) -> Option<T> {
+        if self.count == 0 {
+            None
+        } else {
+            self.count -= 1;
+            unsafe { Some(ptr::read(self.ptr.as_ptr().add(self.count))) }
+        }
+    }
+
     pub fn ptr(&self) -> *const T {
         self.ptr.as_ptr()
     }
+
+    pub fn allocated_bytes(&self) -> usize {
+        self.capacity * std::mem::size_of::<T>()
+    }
+
+    /// Bytes the next push allocates.
+    pub fn growth(&self) -> usize {
+        if self.count == self.capacity {
+            (memory::grow_capacity(self.capacity) - self.capacity) * std::mem::size_of::<T>()
+        } else {
+            0
+        }
+    }
 }
 
 impl<T> Drop for LoxVector<T> {
@@ -144,6 +166,17 @@ mod tests {
         assert_eq!(vec.capacity, 4096);
     }
 
+    #[test]
+    fn pop() {
+        let mut vec = LoxVector::new();
+        vec.push(Op::Pop);
+        vec.push(Op::Constant(1));
+        assert_eq!(vec.pop(), Some(Op::Constant(1)));
+        assert_eq!(vec.pop(), Some(Op::Pop));
+        assert_eq!(vec.pop(), None);
+        assert_eq!(vec.capacity, 8);
+    }
+
     #[test]
     fn slices_work() {
         let mut vec = LoxVector::new();
@@ -1,4 +1,4 @@
-use miette::{Diagnostic, Report};
+use miette::{Diagnostic, Report, SourceSpan};
 use thiserror::Error;
 
 #[derive(Error, Diagnostic, Debug)]
@@ -12,4 +12,38 @@ pub enum InterpreterError {
         error: Report,
         stacktrace: String,
     },
+    /// The VM stopped and can continue with [`crate::vm::VM::resume`] after a new budget is set.
+    #[error("Instruction budget exhausted")]
+    InstructionBudgetExhausted,
+    /// The VM stopped and can continue with [`crate::vm::VM::resume`] after a new deadline is set.
+    #[error("Deadline exceeded")]
+    DeadlineExceeded,
+}
+
+/// Raised by `throw`, the thrown value is on top of the stack until a handler catches it.
+#[derive(Error, Diagnostic, Debug)]
+#[error("Uncaught exception: {description}")]
+pub struct Thrown {
+    pub description: String,
+    #[label("here")]
+    pub location: SourceSpan,
+}
+
+/// Raised through an [`crate::vm::InterruptHandle`], scripts can not catch it.
+#[derive(Error, Diagnostic, Debug)]
+#[error("interrupted")]
+pub struct Interrupted {
+    #[label("here")]
+    pub location: SourceSpan,
+}
+
+/// A module with compile errors, they point into the source of the module.
+#[derive(Error, Diagnostic, Debug)]
+#[error("Unable to import {path}")]
+pub struct ImportError {
+    pub path: String,
+    #[label("here")]
+    pub location: SourceSpan,
+    #[related]
+    pub errors: Vec<Report>,
 }
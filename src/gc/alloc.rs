@@ -1,9 +1,11 @@
 use crate::types::{obj::Obj, obj_ref::ObjRef, string::LoxString};
 
-use super::Gc;
+use super::{Gc, Node};
 
 pub trait Alloc {
     fn alloc(self, gc: &mut Gc) -> ObjRef;
+    // bytes added to the heap at most, interned strings may add nothing
+    fn size(&self) -> usize;
 }
 
 impl Alloc for String {
@@ -12,6 +14,10 @@ impl Alloc for String {
             .find_string(&self)
             .unwrap_or_else(|| gc.manage_lox_string(LoxString::string(self)))
     }
+
+    fn size(&self) -> usize {
+        size_of::<Node>() + self.capacity()
+    }
 }
 
 impl Alloc for &str {
@@ -20,10 +26,18 @@ impl Alloc for &str {
             .find_string(self)
             .unwrap_or_else(|| gc.manage_lox_string(LoxString::from_str(self)))
     }
+
+    fn size(&self) -> usize {
+        size_of::<Node>() + self.len()
+    }
 }
 
 impl Alloc for Obj {
     fn alloc(self, gc: &mut Gc) -> ObjRef {
         gc.add_to_gc(self)
     }
+
+    fn size(&self) -> usize {
+        size_of::<Node>() + self.allocated_bytes()
+    }
 }
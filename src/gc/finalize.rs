@@ -0,0 +1,48 @@
+use crate::types::{obj_ref::ObjRef, value::Value};
+
+use super::Gc;
+
+impl Gc {
+    /// Instance of a class with a `finalize` method, it is run once the instance becomes unreachable.
+    pub fn register_finalizer(&mut self, instance: ObjRef) {
+        self.finalizable.push(instance);
+    }
+
+    /// Marking is done: unreachable objects with a finalizer are resurrected, together with everything they reference.
+    /// They stay alive until their finalizer ran, afterwards they are collected like any other object.
+    pub fn queue_finalizers(&mut self) {
+        let mut i = 0;
+        while i < self.finalizable.len() {
+            let mut obj = self.finalizable[i];
+            if self.is_live(Value::Obj(obj)) {
+                i += 1;
+            } else {
+                self.finalizable.swap_remove(i);
+                self.mark(&mut obj);
+                self.pending_finalizers.push(obj);
+            }
+        }
+        self.trace_references();
+    }
+
+    // pending finalizers are a root
+    pub fn mark_pending_finalizers(&mut self) {
+        for i in 0..self.pending_finalizers.len() {
+            let mut obj = self.pending_finalizers[i];
+            self.mark(&mut obj);
+        }
+    }
+
+    pub fn has_pending_finalizers(&self) -> bool {
+        !self.pending_finalizers.is_empty()
+    }
+
+    pub fn next_finalizer(&mut self) -> Option<ObjRef> {
+        self.pending_finalizers.pop()
+    }
+
+    // the vm is dropped, so every registered finalizer has to run
+    pub fn finalize_all(&mut self) {
+        self.pending_finalizers.append(&mut self.finalizable);
+    }
+}
@@ -17,9 +17,17 @@ impl Markable for ObjRef {
                 self.0.as_ptr(),
                 self.0.as_ref().obj_struct.obj
             );
+            if let Some(references) = gc.recording.as_mut() {
+                references.push(*self);
+                return;
+            }
             if self.0.as_ref().obj_struct.marked {
                 return;
             }
+            // old objects are considered live in a young collection
+            if gc.collecting_young && self.0.as_ref().obj_struct.old {
+                return;
+            }
             self.0.as_mut().obj_struct.marked = true;
             gc.grey(*self);
         }
@@ -1,5 +1,11 @@
 pub mod alloc;
+mod finalize;
 pub mod markable;
+pub mod snapshot;
+pub mod stats;
+#[cfg(feature = "verify_gc")]
+pub mod verify;
+mod weak;
 use std::{ops::DerefMut, ptr::NonNull};
 
 use alloc::Alloc;
@@ -17,27 +23,70 @@ use crate::{
 };
 
 pub struct Gc {
-    head: Option<NonNull<Node>>,
+    // nursery, new objects are allocated here and promoted when surviving a collection
+    young: Option<NonNull<Node>>,
+    old: Option<NonNull<Node>>,
+    // old objects not yet visited by an incremental sweep
+    sweeping: Option<NonNull<Node>>,
+    phase: Phase,
     grey: Vec<ObjRef>,
+    // old objects that may reference young ones, maintained by write_barrier
+    remembered: Vec<ObjRef>,
     strings: HashTable,
+    // nodes, the bytes their objects own including buffers and the buffer of the string pool
     bytes_allocated: usize,
+    young_bytes_allocated: usize,
     next_gc: usize,
+    max_heap_size: usize,
+    collecting_young: bool,
+    // weak refs and weak maps, their referents are cleared once they die
+    weak: Vec<ObjRef>,
+    // instances with a finalizer, pending ones are unreachable and wait for it to run
+    finalizable: Vec<ObjRef>,
+    pending_finalizers: Vec<ObjRef>,
+    // freed nodes are kept to detect dangling references
+    #[cfg(feature = "verify_gc")]
+    quarantine: Vec<NonNull<Node>>,
+    // references found by marking while taking a heap snapshot
+    recording: Option<Vec<ObjRef>>,
 }
 
 pub struct Node {
     next: Option<NonNull<Node>>,
     pub obj_struct: ObjStruct,
 }
+
+/// Phase of an incremental collection of the whole heap.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Phase {
+    Idle,
+    Mark,
+    Sweep,
+}
 static GC_GROW_FACTOR: usize = 2;
+static NURSERY_SIZE: usize = 256 * 1024;
 
 impl Gc {
     pub fn new() -> Self {
         Self {
-            head: None,
+            young: None,
+            old: None,
+            sweeping: None,
+            phase: Phase::Idle,
             grey: vec![],
+            remembered: vec![],
             strings: HashTable::new(),
             bytes_allocated: 0,
+            young_bytes_allocated: 0,
             next_gc: 1024 * 1024,
+            max_heap_size: usize::MAX,
+            collecting_young: false,
+            weak: vec![],
+            finalizable: vec![],
+            pending_finalizers: vec![],
+            #[cfg(feature = "verify_gc")]
+            quarantine: vec![],
+            recording: None,
         }
     }
 
@@ -45,8 +94,115 @@ impl Gc {
         self.bytes_allocated() > self.next_gc()
     }
 
+    pub fn set_max_heap_size(&mut self, max_heap_size: usize) {
+        self.max_heap_size = max_heap_size;
+    }
+
+    pub fn exceeds_max_heap_size(&self, size: usize) -> bool {
+        self.bytes_allocated().saturating_add(size) > self.max_heap_size
+    }
+
+    // young collections are suspended while a collection of the whole heap is running
+    pub fn should_collect_young(&self) -> bool {
+        self.phase == Phase::Idle && self.young_bytes_allocated > NURSERY_SIZE
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    // objects allocated from now on are born marked, so they survive this cycle
+    pub fn begin_mark(&mut self) {
+        self.phase = Phase::Mark;
+    }
+
+    // returns whether the grey worklist is empty after blackening at most budget objects
+    pub fn trace_slice(&mut self, budget: usize) -> bool {
+        for _ in 0..budget {
+            if let Some(mut obj) = self.grey.pop() {
+                self.blacken(&mut obj)
+            } else {
+                break;
+            }
+        }
+        self.grey.is_empty()
+    }
+
+    // marking is done: the nursery is swept at once, old objects incrementally by sweep_slice
+    pub fn begin_sweep(&mut self) {
+        self.queue_finalizers();
+        self.clean_stringpool();
+        self.clear_weak_references();
+        // promoted survivors must not end up in the old objects still to be swept
+        self.sweeping = self.old.take();
+        self.sweep_young();
+        self.phase = Phase::Sweep;
+    }
+
+    // returns whether the sweep is done after visiting at most budget objects
+    pub fn sweep_slice(&mut self, budget: usize) -> bool {
+        for _ in 0..budget {
+            let Some(mut o) = self.sweeping else {
+                break;
+            };
+            unsafe {
+                self.sweeping = o.as_ref().next;
+                if o.as_ref().obj_struct.marked {
+                    o.as_mut().obj_struct.marked = false;
+                    o.as_mut().next = self.old;
+                    self.old = Some(o);
+                } else {
+                    self.free(o);
+                }
+            }
+        }
+        if self.sweeping.is_none() {
+            self.phase = Phase::Idle;
+        }
+        self.phase == Phase::Idle
+    }
+
+    // the following mark, trace and sweep only consider young objects, old ones are treated as live
+    pub fn begin_young_collection(&mut self) {
+        self.collecting_young = true;
+    }
+
+    // remembered objects are roots of a young collection
+    pub fn mark_remembered(&mut self) {
+        for i in 0..self.remembered.len() {
+            let mut obj = self.remembered[i];
+            self.blacken(&mut obj);
+        }
+    }
+
+    /// Has to be called after storing value into owner, so that a young value referenced by an old owner survives young collections.
+    /// While marking, the value is shaded, so a black owner never references a white object.
+    pub fn write_barrier(&mut self, owner: ObjRef, mut value: Value) {
+        if self.phase == Phase::Mark {
+            self.mark(&mut value);
+        }
+        if let Value::Obj(obj) = value {
+            if !obj.is_old() {
+                self.remember(owner);
+            }
+        }
+    }
+
+    /// Write barrier for stores of possibly many references into owner.
+    /// While marking, an already marked owner is traced again.
+    pub fn remember(&mut self, mut owner: ObjRef) {
+        let obj_struct = unsafe { &mut owner.0.as_mut().obj_struct };
+        if self.phase == Phase::Mark && obj_struct.marked {
+            self.grey(owner);
+        }
+        if obj_struct.old && !obj_struct.remembered {
+            obj_struct.remembered = true;
+            self.remembered.push(owner);
+        }
+    }
+
     pub fn reset_next_gc(&mut self) {
-        self.next_gc = self.bytes_allocated * GC_GROW_FACTOR
+        self.next_gc = self.bytes_allocated() * GC_GROW_FACTOR
     }
 
     pub fn alloc(&mut self, object: impl Alloc) -> ObjRef {
@@ -58,36 +214,41 @@ impl Gc {
     }
 
     pub fn trace_references(&mut self) {
-        while let Some(mut obj) = self.grey.pop() {
-            self.blacken(&mut obj)
+        loop {
+            while let Some(mut obj) = self.grey.pop() {
+                self.blacken(&mut obj)
+            }
+            if !self.mark_ephemerons() {
+                break;
+            }
         }
     }
 
     pub fn clean_stringpool(&mut self) {
-        self.strings.remove_white();
+        self.strings.remove_white(self.collecting_young);
     }
 
-    pub fn sweep(&mut self) {
-        let mut previous: Option<NonNull<Node>> = None;
-        let mut object = self.head;
+    // survivors of the young generation are promoted, so no old object references a young one afterwards
+    pub fn sweep_young(&mut self) {
+        let mut object = self.young.take();
         while let Some(mut o) = object {
             unsafe {
+                object = o.as_ref().next;
                 if o.as_ref().obj_struct.marked {
                     o.as_mut().obj_struct.marked = false;
-                    previous = object;
-                    object = o.as_ref().next
+                    o.as_mut().obj_struct.old = true;
+                    o.as_mut().next = self.old;
+                    self.old = Some(o);
                 } else {
-                    let unreached = o;
-                    self.free(unreached);
-                    object = o.as_ref().next;
-                    if let Some(mut prev) = previous {
-                        prev.as_mut().next = object;
-                    } else {
-                        self.head = object;
-                    }
+                    self.free(o);
                 }
             }
         }
+        self.young_bytes_allocated = 0;
+        for mut obj in self.remembered.drain(..) {
+            unsafe { obj.0.as_mut().obj_struct.remembered = false };
+        }
+        self.collecting_young = false;
     }
 
     fn grey(&mut self, obj: ObjRef) {
@@ -116,6 +277,12 @@ impl Gc {
             Obj::Class(class) => self.mark(class),
             Obj::Instance(instance) => self.mark(instance),
             Obj::BoundMethod(bound_method) => self.mark(bound_method),
+            Obj::Shape(shape) => self.mark(shape),
+            Obj::Module(module) => self.mark(module),
+            Obj::List(items) => items.iter_mut().for_each(|item| self.mark(item)),
+            // traced by mark_ephemerons
+            Obj::WeakRef(_) | Obj::WeakMap(_) => (),
+            Obj::Foreign(_) => (),
         }
     }
 
@@ -123,54 +290,80 @@ impl Gc {
         let obj = Obj::String(lox_string);
         let obj_ref = self.add_to_gc(obj);
         // intern the string
+        let before = self.strings.allocated_bytes();
         self.strings.insert(Value::Obj(obj_ref), Value::Nil);
+        self.charge(before, self.strings.allocated_bytes());
         obj_ref
     }
 
     fn add_to_gc(&mut self, obj: Obj) -> ObjRef {
-        self.bytes_allocated += size_of::<Node>();
+        let size = size_of::<Node>() + obj.allocated_bytes();
+        self.bytes_allocated += size;
+        self.young_bytes_allocated += size;
         unsafe {
-            let old_head = self.head.take();
+            let old_head = self.young.take();
+            let mut obj_struct = ObjStruct::new(obj);
+            obj_struct.marked = self.phase == Phase::Mark;
             let new_node = Box::into_raw(Box::new(Node {
                 next: old_head,
-                obj_struct: ObjStruct::new(obj),
+                obj_struct,
             }));
             let new_node = NonNull::new_unchecked(new_node);
             let obj_ptr: *mut Node = new_node.as_ptr();
             let obj_ref = ObjRef::new(NonNull::new_unchecked(obj_ptr));
-            self.head = Some(new_node);
+            self.young = Some(new_node);
+            self.register_weak(obj_ref);
+            if self.phase == Phase::Mark {
+                // the object may be initialized with references to white objects
+                self.grey(obj_ref);
+            }
             debug!("{:p} allocate {}", obj_ptr, *obj_ref);
             obj_ref
         }
     }
 
-    fn free(&mut self, ptr: NonNull<Node>) -> Box<Node> {
-        self.bytes_allocated -= size_of::<Node>();
+    fn free(&mut self, ptr: NonNull<Node>) {
         unsafe {
             let node = ptr.as_ptr();
+            self.bytes_allocated -= size_of::<Node>() + (*node).obj_struct.obj.allocated_bytes();
             debug!(
                 "{:p} free {}",
                 &((*node).obj_struct),
                 (*node).obj_struct.obj
             );
-            Box::from_raw(node)
+            if let Obj::Foreign(foreign) = &(*node).obj_struct.obj {
+                // the drop hook runs when the node is dropped
+                debug!("{:p} finalize {}", &((*node).obj_struct), foreign);
+            }
+            #[cfg(feature = "verify_gc")]
+            self.quarantine(ptr);
+            #[cfg(not(feature = "verify_gc"))]
+            drop(Box::from_raw(node));
         }
     }
 
+    // the stack, frames and tables of the vm are not part of the heap
     pub fn bytes_allocated(&self) -> usize {
         self.bytes_allocated
     }
 
+    /// Accounts the growth of the buffers of an object, from its allocated bytes before to after.
+    pub fn charge(&mut self, before: usize, after: usize) {
+        self.bytes_allocated += after - before;
+    }
+
     pub fn next_gc(&self) -> usize {
         self.next_gc
     }
 
     pub fn heapdump(&self) {
-        unsafe {
-            let mut cur_link = self.head;
-            while let Some(boxed_node) = cur_link {
-                println!("{:?}", boxed_node.as_ref().obj_struct.obj);
-                cur_link = boxed_node.as_ref().next;
+        for head in [self.young, self.old, self.sweeping] {
+            unsafe {
+                let mut cur_link = head;
+                while let Some(boxed_node) = cur_link {
+                    println!("{:?}", boxed_node.as_ref().obj_struct.obj);
+                    cur_link = boxed_node.as_ref().next;
+                }
             }
         }
     }
@@ -178,9 +371,15 @@ impl Gc {
 
 impl Drop for Gc {
     fn drop(&mut self) {
-        let mut cur_link = self.head.take();
-        while let Some(boxed_node) = cur_link {
-            cur_link = self.free(boxed_node).next.take();
+        for mut head in [self.young.take(), self.old.take(), self.sweeping.take()] {
+            while let Some(node) = head {
+                head = unsafe { node.as_ref().next };
+                self.free(node);
+            }
+        }
+        #[cfg(feature = "verify_gc")]
+        for node in self.quarantine.drain(..) {
+            drop(unsafe { Box::from_raw(node.as_ptr()) });
         }
     }
 }
@@ -188,7 +387,7 @@ impl Drop for Gc {
 #[cfg(test)]
 mod tests {
 
-    use super::*;
+    use super::{stats::ObjStats, *};
 
     #[test]
     fn push() {
@@ -208,4 +407,109 @@ mod tests {
         let two = gc.alloc("asfsaf");
         assert_eq!(one, two);
     }
+
+    fn collect_young(gc: &mut Gc, roots: &mut [ObjRef]) {
+        gc.begin_young_collection();
+        for root in roots {
+            gc.mark(root);
+        }
+        gc.mark_remembered();
+        gc.trace_references();
+        gc.queue_finalizers();
+        gc.clean_stringpool();
+        gc.clear_weak_references();
+        gc.sweep_young();
+    }
+
+    #[test]
+    fn young_collection_promotes_survivors() {
+        let mut gc = Gc::new();
+        let kept = gc.alloc("kept");
+        gc.alloc("garbage");
+        collect_young(&mut gc, &mut [kept]);
+        assert!(kept.is_old());
+        assert_eq!(gc.stats().objects["string"].count, 1);
+
+        // old objects survive young collections without being marked
+        collect_young(&mut gc, &mut []);
+        assert_eq!(gc.stats().objects["string"].count, 1);
+        assert_eq!(gc.alloc("kept"), kept);
+    }
+
+    #[test]
+    fn weak_refs_are_cleared_when_young_targets_die() {
+        let mut gc = Gc::new();
+        let old = gc.alloc("old");
+        collect_young(&mut gc, &mut [old]);
+        let young = gc.alloc("young");
+        let weak_young = gc.alloc(Obj::WeakRef(Value::Obj(young)));
+        let weak_old = gc.alloc(Obj::WeakRef(Value::Obj(old)));
+        collect_young(&mut gc, &mut [weak_young, weak_old]);
+        assert_matches!(*weak_young, Obj::WeakRef(Value::Nil));
+        assert_matches!(*weak_old, Obj::WeakRef(Value::Obj(target)) if target == old);
+        assert_eq!(gc.stats().objects["string"].count, 1);
+    }
+
+    #[test]
+    fn write_barrier_keeps_young_values_of_old_objects() {
+        let mut gc = Gc::new();
+        let mut upvalue = gc.alloc(Obj::Upvalue {
+            location: std::ptr::null_mut(),
+            next: None,
+            closed: Value::Nil,
+        });
+        collect_young(&mut gc, &mut [upvalue]);
+        assert!(upvalue.is_old());
+
+        let young = gc.alloc("young");
+        if let Obj::Upvalue { closed, .. } = upvalue.deref_mut() {
+            *closed = Value::Obj(young);
+        }
+        gc.write_barrier(upvalue, Value::Obj(young));
+        collect_young(&mut gc, &mut []);
+        assert!(young.is_old());
+        assert_eq!(gc.alloc("young"), young);
+    }
+
+    #[test]
+    fn accounts_owned_and_buffer_bytes() {
+        let mut gc = Gc::new();
+        let before = gc.bytes_allocated();
+        let string = "a".repeat(1000);
+        gc.alloc(string);
+        assert!(gc.bytes_allocated() >= before + size_of::<Node>() + 1000);
+
+        let stats = gc.stats();
+        assert_eq!(stats.objects["string"].count, 1);
+        assert_eq!(stats.objects["string"].bytes, size_of::<Node>() + 1000);
+        assert_eq!(stats.objects["instance"], ObjStats::default());
+    }
+
+    #[test]
+    fn write_barrier_shades_values_stored_into_black_objects() {
+        let mut gc = Gc::new();
+        let mut upvalue = gc.alloc(Obj::Upvalue {
+            location: std::ptr::null_mut(),
+            next: None,
+            closed: Value::Nil,
+        });
+        let white = gc.alloc("white");
+        gc.begin_mark();
+        gc.mark(&mut upvalue);
+        assert!(gc.trace_slice(1));
+
+        if let Obj::Upvalue { closed, .. } = upvalue.deref_mut() {
+            *closed = Value::Obj(white);
+        }
+        gc.write_barrier(upvalue, Value::Obj(white));
+        // objects allocated while marking survive the running cycle
+        gc.alloc("allocated while marking");
+        gc.trace_references();
+        gc.begin_sweep();
+        while !gc.sweep_slice(1) {}
+        assert_eq!(gc.phase(), Phase::Idle);
+        let objects = gc.stats().objects;
+        assert_eq!(objects["string"].count, 2);
+        assert_eq!(objects["upvalue"].count, 1);
+    }
 }
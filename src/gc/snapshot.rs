@@ -0,0 +1,295 @@
+use std::{collections::HashMap, fmt::Write as _};
+
+use crate::types::obj_ref::ObjRef;
+
+use super::{Gc, Node};
+
+/// What keeps an object alive, in the order roots are marked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Root {
+    Stack,
+    Frame,
+    Upvalue,
+    Global,
+    Module,
+    InitString,
+    EmptyShape,
+    ErrorClass,
+    PendingFinalizer,
+}
+
+impl Root {
+    pub const ALL: [Root; 9] = [
+        Root::Stack,
+        Root::Frame,
+        Root::Upvalue,
+        Root::Global,
+        Root::Module,
+        Root::InitString,
+        Root::EmptyShape,
+        Root::ErrorClass,
+        Root::PendingFinalizer,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Root::Stack => "stack",
+            Root::Frame => "frame",
+            Root::Upvalue => "upvalue",
+            Root::Global => "global",
+            Root::Module => "module",
+            Root::InitString => "init string",
+            Root::EmptyShape => "empty shape",
+            Root::ErrorClass => "error class",
+            Root::PendingFinalizer => "pending finalizer",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotObject {
+    pub id: usize,
+    pub kind: &'static str,
+    pub size: usize,
+    /// Bytes freed if this object died, itself and everything only reachable through it.
+    pub retained_size: usize,
+    pub root: Root,
+    pub description: String,
+    pub references: Vec<usize>,
+}
+
+/// Live objects reachable from the roots, ids are indices into `objects`.
+#[derive(Debug, Clone, Default)]
+pub struct HeapSnapshot {
+    pub objects: Vec<SnapshotObject>,
+}
+
+impl Gc {
+    // references are collected by blacken, but nothing is marked while recording
+    pub(crate) fn begin_recording(&mut self) {
+        self.recording = Some(vec![]);
+    }
+
+    pub(crate) fn end_recording(&mut self) -> Vec<ObjRef> {
+        self.recording.take().unwrap_or_default()
+    }
+
+    pub(super) fn references(&mut self, mut obj: ObjRef) -> Vec<ObjRef> {
+        self.begin_recording();
+        self.blacken(&mut obj);
+        self.end_recording()
+    }
+
+    /// Objects are visited breadth first, each is attributed to the first root it is found from.
+    pub fn snapshot(&mut self, roots: Vec<(Root, Vec<ObjRef>)>) -> HeapSnapshot {
+        let mut ids: HashMap<*mut Node, usize> = HashMap::new();
+        let mut objects: Vec<(ObjRef, Root)> = vec![];
+        let mut root_ids = vec![];
+        for (root, refs) in roots {
+            for obj in refs {
+                let id = *ids.entry(obj.0.as_ptr()).or_insert_with(|| {
+                    objects.push((obj, root));
+                    objects.len() - 1
+                });
+                root_ids.push(id);
+            }
+        }
+
+        let mut references = vec![];
+        let mut next = 0;
+        while next < objects.len() {
+            let (obj, root) = objects[next];
+            let mut edges = vec![];
+            for reference in self.references(obj) {
+                let id = *ids.entry(reference.0.as_ptr()).or_insert_with(|| {
+                    objects.push((reference, root));
+                    objects.len() - 1
+                });
+                edges.push(id);
+            }
+            references.push(edges);
+            next += 1;
+        }
+
+        let sizes: Vec<usize> = objects
+            .iter()
+            .map(|(obj, _)| size_of::<Node>() + obj.allocated_bytes())
+            .collect();
+        let retained_sizes = retained_sizes(&root_ids, &references, &sizes);
+
+        let objects = objects
+            .into_iter()
+            .zip(references)
+            .enumerate()
+            .map(|(id, ((obj, root), references))| SnapshotObject {
+                id,
+                kind: obj.kind(),
+                size: sizes[id],
+                retained_size: retained_sizes[id],
+                root,
+                description: obj.to_string(),
+                references,
+            })
+            .collect();
+        HeapSnapshot { objects }
+    }
+}
+
+// dominators as in "A Simple, Fast Dominance Algorithm" by Cooper, Harvey and Kennedy,
+// a virtual node n references all roots
+fn retained_sizes(roots: &[usize], references: &[Vec<usize>], sizes: &[usize]) -> Vec<usize> {
+    let n = references.len();
+    let successors = |node: usize| -> &[usize] {
+        if node == n {
+            roots
+        } else {
+            &references[node]
+        }
+    };
+
+    let mut predecessors = vec![vec![]; n + 1];
+    let mut postorder = Vec::with_capacity(n + 1);
+    let mut visited = vec![false; n + 1];
+    let mut stack = vec![(n, 0)];
+    visited[n] = true;
+    while let Some((node, edge)) = stack.pop() {
+        if let Some(&successor) = successors(node).get(edge) {
+            stack.push((node, edge + 1));
+            predecessors[successor].push(node);
+            if !visited[successor] {
+                visited[successor] = true;
+                stack.push((successor, 0));
+            }
+        } else {
+            postorder.push(node);
+        }
+    }
+    let mut order = vec![0; n + 1];
+    for (index, node) in postorder.iter().enumerate() {
+        order[*node] = index;
+    }
+
+    let mut idom = vec![None; n + 1];
+    idom[n] = Some(n);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &node in postorder.iter().rev().skip(1) {
+            let mut new_idom: Option<usize> = None;
+            for &predecessor in &predecessors[node] {
+                if idom[predecessor].is_none() {
+                    continue;
+                }
+                new_idom = Some(match new_idom {
+                    None => predecessor,
+                    Some(mut a) => {
+                        let mut b = predecessor;
+                        while a != b {
+                            while order[a] < order[b] {
+                                a = idom[a].unwrap();
+                            }
+                            while order[b] < order[a] {
+                                b = idom[b].unwrap();
+                            }
+                        }
+                        a
+                    }
+                });
+            }
+            if new_idom.is_some() && idom[node] != new_idom {
+                idom[node] = new_idom;
+                changed = true;
+            }
+        }
+    }
+
+    let mut retained: Vec<usize> = sizes.to_vec();
+    retained.push(0);
+    // children come before their dominator in postorder
+    for &node in &postorder {
+        if node != n {
+            let dominator = idom[node].unwrap();
+            retained[dominator] += retained[node];
+        }
+    }
+    retained.truncate(n);
+    retained
+}
+
+impl HeapSnapshot {
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\"objects\":[");
+        for (i, object) in self.objects.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let _ = write!(
+                json,
+                "{{\"id\":{},\"kind\":\"{}\",\"size\":{},\"retained_size\":{},\"root\":\"{}\",\"description\":",
+                object.id,
+                object.kind,
+                object.size,
+                object.retained_size,
+                object.root.name()
+            );
+            write_json_string(&mut json, &object.description);
+            json.push_str(",\"references\":[");
+            for (j, reference) in object.references.iter().enumerate() {
+                if j > 0 {
+                    json.push(',');
+                }
+                let _ = write!(json, "{}", reference);
+            }
+            json.push_str("]}");
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+fn write_json_string(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retained_sizes_follow_dominators() {
+        // 0 -> 1 -> 2, 0 -> 3 -> 2, 1 -> 4
+        let references = vec![vec![1, 3], vec![2, 4], vec![], vec![2], vec![]];
+        let sizes = vec![1, 10, 100, 1000, 10000];
+        assert_eq!(
+            retained_sizes(&[0], &references, &sizes),
+            vec![11111, 10010, 100, 1000, 10000]
+        );
+        // 2 is shared by two roots, so neither retains it
+        let references = vec![vec![2], vec![2], vec![]];
+        assert_eq!(
+            retained_sizes(&[0, 1], &references, &[1, 10, 100]),
+            vec![1, 10, 100]
+        );
+    }
+
+    #[test]
+    fn strings_are_escaped() {
+        let mut json = String::new();
+        write_json_string(&mut json, "a\"b\\c\n\u{1}");
+        assert_eq!(json, r#""a\"b\\c\n\u0001""#);
+    }
+}
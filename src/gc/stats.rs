@@ -0,0 +1,45 @@
+use std::{collections::BTreeMap, ptr::NonNull};
+
+use crate::types::obj::Obj;
+
+use super::{Gc, Node};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ObjStats {
+    pub count: usize,
+    pub bytes: usize,
+}
+
+/// Snapshot of the heap, objects are grouped by `Obj::kind`.
+#[derive(Debug, Default, Clone)]
+pub struct GcStats {
+    pub bytes_allocated: usize,
+    pub next_gc: usize,
+    pub objects: BTreeMap<&'static str, ObjStats>,
+}
+
+impl Gc {
+    // objects not collected yet are included
+    pub fn stats(&self) -> GcStats {
+        let mut objects: BTreeMap<_, _> = Obj::KINDS
+            .iter()
+            .map(|kind| (*kind, ObjStats::default()))
+            .collect();
+        for head in [self.young, self.old, self.sweeping] {
+            let mut cur_link: Option<NonNull<Node>> = head;
+            while let Some(node) = cur_link {
+                let node = unsafe { node.as_ref() };
+                let obj = &node.obj_struct.obj;
+                let stats = objects.entry(obj.kind()).or_default();
+                stats.count += 1;
+                stats.bytes += size_of::<Node>() + obj.allocated_bytes();
+                cur_link = node.next;
+            }
+        }
+        GcStats {
+            bytes_allocated: self.bytes_allocated(),
+            next_gc: self.next_gc(),
+            objects,
+        }
+    }
+}
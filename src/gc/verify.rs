@@ -0,0 +1,157 @@
+use std::{collections::HashSet, ptr::NonNull, sync::Arc};
+
+use miette::{LabeledSpan, NamedSource, SourceSpan};
+
+use crate::types::{obj::Obj, obj_ref::ObjRef, value::Value};
+
+use super::{snapshot::Root, Gc, Node};
+
+/// Bookkeeping of every object to check the heap after collections.
+#[derive(Default)]
+pub struct VerifyInfo {
+    // none for objects allocated by the compiler or the vm itself
+    pub alloc_site: Option<(SourceSpan, Arc<NamedSource<String>>)>,
+    // kind of the object the node held before it was freed
+    pub freed: Option<&'static str>,
+}
+
+// where a reference was found, for reporting
+enum Referrer {
+    Root(Root),
+    Obj(ObjRef),
+    Strings,
+}
+
+impl Gc {
+    // freed objects are dropped, so resources are released, but their node stays allocated until the gc is dropped
+    pub(super) fn quarantine(&mut self, mut ptr: NonNull<Node>) {
+        let obj_struct = unsafe { &mut ptr.as_mut().obj_struct };
+        obj_struct.verify.freed = Some(obj_struct.obj.kind());
+        drop(std::mem::replace(
+            &mut obj_struct.obj,
+            Obj::WeakRef(Value::Nil),
+        ));
+        self.quarantine.push(ptr);
+    }
+
+    pub fn set_alloc_site(
+        &mut self,
+        mut obj: ObjRef,
+        span: SourceSpan,
+        source: Arc<NamedSource<String>>,
+    ) {
+        unsafe { obj.0.as_mut().obj_struct.verify.alloc_site = Some((span, source)) };
+    }
+
+    /// Panics if a reachable object was freed, an interned string was freed, a mark bit is still set
+    /// or the accounted bytes differ from the objects on the heap.
+    /// Only valid while no collection is running.
+    pub fn verify(&mut self, roots: Vec<(Root, Vec<ObjRef>)>) {
+        let mut visited = HashSet::new();
+        let mut worklist: Vec<(Referrer, ObjRef)> = roots
+            .into_iter()
+            .flat_map(|(root, objs)| objs.into_iter().map(move |obj| (Referrer::Root(root), obj)))
+            .collect();
+        while let Some((referrer, obj)) = worklist.pop() {
+            self.verify_not_freed(&referrer, obj);
+            if visited.insert(obj.0.as_ptr()) {
+                for reference in self.references(obj) {
+                    worklist.push((Referrer::Obj(obj), reference));
+                }
+            }
+        }
+
+        for (key, _) in self.strings.entries() {
+            self.verify_not_freed(&Referrer::Strings, *key.as_obj());
+        }
+
+        let mut bytes_allocated = self.strings.allocated_bytes();
+        for head in [self.young, self.old, self.sweeping] {
+            let mut cur_link = head;
+            while let Some(node) = cur_link {
+                let obj = ObjRef::new(node);
+                if unsafe { node.as_ref().obj_struct.marked } {
+                    fail(obj, "object is still marked after the collection", vec![]);
+                }
+                bytes_allocated += size_of::<Node>() + obj.allocated_bytes();
+                cur_link = unsafe { node.as_ref().next };
+            }
+        }
+        assert_eq!(
+            bytes_allocated, self.bytes_allocated,
+            "bytes_allocated does not match the objects on the heap, a buffer grew without being charged"
+        );
+    }
+
+    fn verify_not_freed(&self, referrer: &Referrer, obj: ObjRef) {
+        let Some(kind) = (unsafe { obj.0.as_ref().obj_struct.verify.freed }) else {
+            return;
+        };
+        let (message, referrer_site) = match referrer {
+            Referrer::Root(root) => (
+                format!("{} root references freed {}", root.name(), kind),
+                None,
+            ),
+            Referrer::Obj(referrer) => (
+                // displaying the referrer could follow the dangling reference
+                format!("{} references freed {}", referrer.kind(), kind),
+                Some(*referrer),
+            ),
+            Referrer::Strings => (format!("string pool references freed {}", kind), None),
+        };
+        let related = referrer_site.into_iter().collect();
+        fail(obj, &message, related)
+    }
+}
+
+// reports the allocation site of obj and of the related objects
+fn fail(obj: ObjRef, message: &str, related: Vec<ObjRef>) -> ! {
+    let mut report = format!("verify_gc: {message}\n");
+    for (label, obj) in std::iter::once(("allocated here", obj)).chain(
+        related
+            .into_iter()
+            .map(|obj| ("referrer allocated here", obj)),
+    ) {
+        match unsafe { &obj.0.as_ref().obj_struct.verify.alloc_site } {
+            Some((span, source)) => {
+                let diagnostic =
+                    miette::miette!(labels = vec![LabeledSpan::at(*span, label)], "{label}")
+                        .with_source_code(NamedSource::clone(source));
+                report.push_str(&format!("{:?}\n", diagnostic));
+            }
+            None => report.push_str(&format!("{label}: by the compiler or the vm\n")),
+        }
+    }
+    panic!("{report}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "upvalue references freed string")]
+    fn dangling_references_are_detected() {
+        let mut gc = Gc::new();
+        let string = gc.alloc("not rooted");
+        gc.begin_young_collection();
+        gc.trace_references();
+        gc.clean_stringpool();
+        gc.sweep_young();
+        let upvalue = gc.alloc(Obj::Upvalue {
+            location: std::ptr::null_mut(),
+            next: None,
+            closed: Value::Obj(string),
+        });
+        gc.verify(vec![(Root::Stack, vec![upvalue])]);
+    }
+
+    #[test]
+    #[should_panic(expected = "object is still marked")]
+    fn mark_bits_are_checked() {
+        let mut gc = Gc::new();
+        let mut string = gc.alloc("marked");
+        gc.mark(&mut string);
+        gc.verify(vec![]);
+    }
+}
@@ -0,0 +1,60 @@
+use crate::types::{obj::Obj, obj_ref::ObjRef, value::Value};
+
+use super::Gc;
+
+impl Gc {
+    // weak refs and weak maps have to be known to the gc, as their referents are not traced
+    pub(super) fn register_weak(&mut self, obj: ObjRef) {
+        if matches!(*obj, Obj::WeakRef(_) | Obj::WeakMap(_)) {
+            self.weak.push(obj);
+        }
+    }
+
+    // old objects are considered live in a young collection
+    pub(super) fn is_live(&self, value: Value) -> bool {
+        match value {
+            Value::Obj(obj) => {
+                let marked = unsafe { obj.0.as_ref().obj_struct.marked };
+                marked || (self.collecting_young && obj.is_old())
+            }
+            _ => true,
+        }
+    }
+
+    // values of weak maps are marked only once their key is known to be live, so a value referencing its key keeps neither alive
+    // returns whether anything new was marked
+    pub(super) fn mark_ephemerons(&mut self) -> bool {
+        for i in 0..self.weak.len() {
+            let weak = self.weak[i];
+            if !self.is_live(Value::Obj(weak)) {
+                continue;
+            }
+            if let Obj::WeakMap(table) = &*weak {
+                for (key, mut value) in table.entries() {
+                    if self.is_live(key) && !self.is_live(value) {
+                        self.mark(&mut value);
+                    }
+                }
+            }
+        }
+        !self.grey.is_empty()
+    }
+
+    /// Marking is done: clears weak refs to dead objects and removes weak map entries with dead keys.
+    pub fn clear_weak_references(&mut self) {
+        let mut weak = std::mem::take(&mut self.weak);
+        weak.retain(|obj| self.is_live(Value::Obj(*obj)));
+        for obj in weak.iter_mut() {
+            match &mut **obj {
+                Obj::WeakRef(target) => {
+                    if !self.is_live(*target) {
+                        *target = Value::Nil;
+                    }
+                }
+                Obj::WeakMap(table) => table.remove_white(self.collecting_young),
+                _ => unreachable!("only weak refs and weak maps are registered"),
+            }
+        }
+        self.weak = weak;
+    }
+}
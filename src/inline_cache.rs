@@ -0,0 +1,99 @@
+use crate::{
+    gc::{markable::Markable, Gc},
+    types::{obj_ref::ObjRef, value::Value},
+};
+
+/// Per-instruction cache for `GetProperty`, `SetProperty` and `Invoke`.
+/// Remembers where the field was found for the shape of the last receiver and the method found on the last receiver class.
+#[derive(Debug, Clone, Copy)]
+pub struct InlineCache {
+    shape: Option<ObjRef>,
+    field: CachedField,
+    class: Option<ObjRef>,
+    version: u32,
+    method: Value,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CachedField {
+    Slot(usize),
+    Missing,
+    // field is missing and adding it transitions the receiver to this shape
+    Transition(ObjRef),
+}
+
+impl InlineCache {
+    pub fn new() -> Self {
+        Self {
+            shape: None,
+            field: CachedField::Missing,
+            class: None,
+            version: 0,
+            method: Value::Nil,
+        }
+    }
+
+    pub fn field(&self, shape: ObjRef) -> Option<CachedField> {
+        match self.shape {
+            Some(cached) if cached == shape => Some(self.field),
+            _ => None,
+        }
+    }
+
+    pub fn update_field(&mut self, shape: ObjRef, field: CachedField) {
+        self.shape = Some(shape);
+        self.field = field;
+    }
+
+    // the cached method is only valid as long as the class has not gained methods since
+    pub fn method(&self, class: ObjRef) -> Option<Value> {
+        match self.class {
+            Some(cached) if cached == class && self.version == class.as_class().version() => {
+                Some(self.method)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn update_method(&mut self, class: ObjRef, method: Value) {
+        self.class = Some(class);
+        self.version = class.as_class().version();
+        self.method = method;
+    }
+}
+
+impl Markable for InlineCache {
+    // cached objects are kept alive, otherwise a new object could be allocated at the same address
+    fn mark(&mut self, gc: &mut Gc) {
+        if let Some(shape) = self.shape.as_mut() {
+            shape.mark(gc);
+        }
+        if let CachedField::Transition(shape) = &mut self.field {
+            shape.mark(gc);
+        }
+        if let Some(class) = self.class.as_mut() {
+            class.mark(gc);
+        }
+        self.method.mark(gc);
+    }
+
+    fn is_marked(&mut self) -> bool {
+        unreachable!()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        if self.hits + self.misses == 0 {
+            0.0
+        } else {
+            self.hits as f64 / (self.hits + self.misses) as f64
+        }
+    }
+}
@@ -6,7 +6,7 @@ use rustyline::{
     error::ReadlineError, highlight::MatchingBracketHighlighter,
     validate::MatchingBracketValidator, Completer, Editor, Helper, Highlighter, Hinter, Validator,
 };
-use std::fs;
+use std::{fs, time::Duration};
 use tracing::Level;
 use tracing_subscriber::{filter, layer::SubscriberExt as _, util::SubscriberInitExt as _};
 use vm::VM;
@@ -18,6 +18,7 @@ mod compiler;
 mod datastructures;
 mod error;
 mod gc;
+mod inline_cache;
 mod op;
 mod parser;
 mod printer;
@@ -54,7 +55,15 @@ fn main() {
         .with(filter)
         .init();
 
-    let vm = VM::new();
+    let vm = VM::builder()
+        .max_frames(args.max_frames)
+        .max_stack(args.max_stack)
+        .gc_slice_budget(args.gc_slice_budget)
+        .max_heap_size(args.max_heap_size)
+        .max_instructions(args.max_instructions)
+        .timeout(args.timeout.map(Duration::from_millis))
+        .file_access(args.allow_file_access)
+        .build();
     let result = match args.file {
         Some(file) => run_file(vm, file),
         None => run_prompt(vm, args).into_diagnostic(),
@@ -73,6 +82,11 @@ fn main() {
                         eprintln!("{}", stacktrace);
                         std::process::exit(75)
                     }
+                    InterpreterError::InstructionBudgetExhausted
+                    | InterpreterError::DeadlineExceeded => {
+                        eprintln!("{}", compile_error);
+                        std::process::exit(75)
+                    }
                 }
             } else {
                 eprintln!("{:?}", err);
@@ -99,6 +113,12 @@ fn run_prompt(mut vm: VM, args: Args) -> rustyline::Result<()> {
         highlighter: MatchingBracketHighlighter,
     }
 
+    // the terminal is in raw mode while reading, so SIGINT only arrives while a script runs
+    let interrupt = vm.interrupt_handle();
+    if let Err(err) = ctrlc::set_handler(move || interrupt.interrupt()) {
+        eprintln!("Unable to handle Ctrl-C: {:?}", err);
+    }
+
     let history_file = args.history_file;
     let mut rl = Editor::new()?;
     rl.set_helper(Some(MyHelper::default()));
@@ -113,13 +133,24 @@ fn run_prompt(mut vm: VM, args: Args) -> rustyline::Result<()> {
         match readline {
             Ok(source) => {
                 rl.add_history_entry(source.as_str())?;
-                match vm.interpret(NamedSource::new("repl", source)) {
+                let result = if source.trim() == ":resume" {
+                    vm.set_instruction_budget(args.max_instructions);
+                    vm.set_timeout(args.timeout.map(Duration::from_millis));
+                    vm.resume()
+                } else {
+                    vm.interpret(NamedSource::new("repl", source))
+                };
+                match result {
                     Ok(()) => (),
                     Err(InterpreterError::CompileError(err)) => println!("{:?}", err),
                     Err(InterpreterError::RuntimeError { error, stacktrace }) => {
                         println!("{:?}", error);
                         println!("{}", stacktrace);
                     }
+                    Err(
+                        err @ (InterpreterError::InstructionBudgetExhausted
+                        | InterpreterError::DeadlineExceeded),
+                    ) => println!("{}, enter :resume to continue", err),
                 }
             }
             Err(ReadlineError::Interrupted | ReadlineError::Eof) => break,
@@ -9,6 +9,16 @@ pub enum Op {
     Subtract,
     Multiply,
     Divide,
+    // floored, the result has the sign of the divisor
+    Modulo,
+    FloorDivide,
+    Power,
+    // on integers up to 2^53, the exactly representable ones
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
     Negate,
     Nil,
     True,
@@ -19,6 +29,10 @@ pub enum Op {
     Less,
     Print,
     Pop,
+    // pushes a copy of the value at the distance from the top
+    Dup(u8),
+    // moves the top value below that many values
+    Bury(u8),
     DefineGlobal(u8),
     GetGlobal(u8),
     SetGlobal(u8),
@@ -26,18 +40,69 @@ pub enum Op {
     SetLocal(u8),
     GetUpvalue(u8),
     SetUpvalue(u8),
-    GetProperty(u8),
-    SetProperty(u8),
+    GetProperty {
+        property_index: u8,
+        cache: u16,
+    },
+    SetProperty {
+        property_index: u8,
+        cache: u16,
+    },
     JumpIfFalse(u16),
+    JumpIfNil(u16),
     Jump(u16),
     Loop(u16),
     Call(u8),
+    TailCall(u8),
     Closure(u8),
     CloseUpvalue,
     Class(u8),
     Method(u8),
-    Invoke { property_index: u8, arg_count: u8 },
-    SuperInvoke { property_index: u8, arg_count: u8 },
+    StaticMethod(u8),
+    Invoke {
+        property_index: u8,
+        arg_count: u8,
+        cache: u16,
+    },
+    SuperInvoke {
+        property_index: u8,
+        arg_count: u8,
+    },
     Inherit,
     GetSuper(u8),
+    // the char of a string or the item of a list
+    Index,
+    SetIndex,
+    Import(u8),
+    // joins the display strings of that many values
+    BuildString(u8),
+    Throw,
+    // ends a finally block, the slot holds how it was entered and the next one the pending value
+    EndFinally(u8),
+    // superinstructions, fused by the compiler from common sequences
+    AddLocals {
+        left: u8,
+        right: u8,
+    },
+    JumpIfLocalNotLess {
+        slot: u8,
+        constant: u8,
+        offset: u16,
+    },
+}
+
+impl Op {
+    pub fn with_jump_offset(self, offset: u16) -> Op {
+        match self {
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(offset),
+            Op::JumpIfNil(_) => Op::JumpIfNil(offset),
+            Op::Jump(_) => Op::Jump(offset),
+            Op::JumpIfLocalNotLess { slot, constant, .. } => Op::JumpIfLocalNotLess {
+                slot,
+                constant,
+                offset,
+            },
+            op => unreachable!("{op} is not a forward jump"),
+        }
+    }
 }
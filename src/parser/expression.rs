@@ -1,11 +1,31 @@
 use super::{Parser, Result};
+use std::borrow::Cow;
+
 use crate::{
-    check, consume, match_token,
+    check,
+    compiler::FunctionType,
+    consume, match_token,
     op::Op,
+    source_span_extensions::SourceSpanExtensions,
     token::{Precedence, Token, TokenType},
     types::value::Value,
 };
-use miette::{miette, LabeledSpan, SourceSpan};
+use miette::{miette, LabeledSpan, SourceCode, SourceSpan};
+
+/// What an assignment operator writes to.
+pub(super) enum Target {
+    Variable { get: Op, set: Op },
+    Property(u8),
+    Index,
+}
+
+/// `+=` and the like take an operand, `++` and `--` add or subtract one. Postfix ones evaluate
+/// to the value before the update.
+pub(super) enum Update {
+    Compound(Op),
+    Prefix(Op),
+    Postfix(Op),
+}
 
 impl Parser<'_, '_> {
     pub(super) fn expression(&mut self) -> Result<()> {
@@ -32,7 +52,14 @@ impl Parser<'_, '_> {
         }
 
         if can_assign {
-            if let Some(equal) = match_token!(self.scanner, TokenType::Equal)? {
+            if let Some(equal) = match_token!(
+                self.scanner,
+                TokenType::Equal
+                    | TokenType::PlusEqual
+                    | TokenType::MinusEqual
+                    | TokenType::StarEqual
+                    | TokenType::SlashEqual
+            )? {
                 miette::bail!(
                     labels = vec![LabeledSpan::at(equal.location, "here")],
                     "Invalid assignment target",
@@ -57,17 +84,24 @@ impl Parser<'_, '_> {
 
     fn prefix(&mut self, token: Token, can_assign: bool) -> Result<()> {
         match token.token_type {
+            TokenType::LeftParen if self.scanner.is_arrow_parameter_list() => {
+                self.arrow_function(token.location)?
+            }
             TokenType::LeftParen => self.grouping()?,
+            TokenType::Fun => self.anonymous_function(token.location)?,
             TokenType::Minus => self.unary(Op::Negate, token.location)?,
+            TokenType::PlusPlus => self.prefix_update(Op::Add, token.location)?,
+            TokenType::MinusMinus => self.prefix_update(Op::Subtract, token.location)?,
             TokenType::Bang => self.unary(Op::Not, token.location)?,
             TokenType::Number(f) => self.current.emit_constant(Value::Number(f), token.location),
             TokenType::Nil => self.current.chunk.write(Op::Nil, token.location),
             TokenType::True => self.current.chunk.write(Op::True, token.location),
             TokenType::False => self.current.chunk.write(Op::False, token.location),
             TokenType::String(s) => {
-                let obj = self.gc.alloc(s);
+                let obj = self.gc.alloc(s.as_ref());
                 self.current.emit_constant(Value::Obj(obj), token.location)
             }
+            TokenType::Interpolation(s) => self.interpolation(s, token.location)?,
             TokenType::Identifier(name) => self.named_variable(name, can_assign, token.location)?,
             TokenType::This => self.this(token.location)?,
             TokenType::Super => self._super(token.location)?,
@@ -82,6 +116,23 @@ impl Parser<'_, '_> {
             TokenType::Plus => self.binary(Op::Add, None, Precedence::Factor, token.location),
             TokenType::Star => self.binary(Op::Multiply, None, Precedence::Unary, token.location),
             TokenType::Slash => self.binary(Op::Divide, None, Precedence::Unary, token.location),
+            TokenType::Percent => self.binary(Op::Modulo, None, Precedence::Unary, token.location),
+            TokenType::TildeSlash => {
+                self.binary(Op::FloorDivide, None, Precedence::Unary, token.location)
+            }
+            // right associative
+            TokenType::StarStar => self.binary(Op::Power, None, Precedence::Power, token.location),
+            TokenType::Pipe => self.binary(Op::BitOr, None, Precedence::BitXor, token.location),
+            TokenType::Caret => self.binary(Op::BitXor, None, Precedence::BitAnd, token.location),
+            TokenType::Ampersand => {
+                self.binary(Op::BitAnd, None, Precedence::Shift, token.location)
+            }
+            TokenType::LessLess => {
+                self.binary(Op::ShiftLeft, None, Precedence::Term, token.location)
+            }
+            TokenType::GreaterGreater => {
+                self.binary(Op::ShiftRight, None, Precedence::Term, token.location)
+            }
             TokenType::BangEqual => self.binary(
                 Op::Equal,
                 Some(Op::Not),
@@ -91,17 +142,26 @@ impl Parser<'_, '_> {
             TokenType::EqualEqual => {
                 self.binary(Op::Equal, None, Precedence::Comparision, token.location)
             }
-            TokenType::Greater => self.binary(Op::Greater, None, Precedence::Term, token.location),
+            TokenType::Greater => self.binary(Op::Greater, None, Precedence::BitOr, token.location),
             TokenType::GreaterEqual => {
-                self.binary(Op::Less, Some(Op::Not), Precedence::Term, token.location)
-            }
-            TokenType::Less => self.binary(Op::Less, None, Precedence::Term, token.location),
-            TokenType::LessEqual => {
-                self.binary(Op::Greater, Some(Op::Not), Precedence::Term, token.location)
+                self.binary(Op::Less, Some(Op::Not), Precedence::BitOr, token.location)
             }
+            TokenType::Less => self.binary(Op::Less, None, Precedence::BitOr, token.location),
+            TokenType::LessEqual => self.binary(
+                Op::Greater,
+                Some(Op::Not),
+                Precedence::BitOr,
+                token.location,
+            ),
             TokenType::And => self.and(token.location),
             TokenType::Or => self.or(token.location),
+            TokenType::QuestionQuestion => self.coalesce(token.location),
+            TokenType::Question => self.conditional(token.location),
+            TokenType::QuestionDot => self.optional_dot(token.location),
             TokenType::LeftParen => self.call(token.location),
+            TokenType::LeftBracket => self.index(token.location, can_assign),
+            TokenType::PlusPlus => self.postfix_update(Op::Add, token.location),
+            TokenType::MinusMinus => self.postfix_update(Op::Subtract, token.location),
             TokenType::Dot => self.dot(token.location, can_assign),
             _ => unreachable!(), // guarded by infix_precedence
         }
@@ -154,6 +214,25 @@ impl Parser<'_, '_> {
         Ok(())
     }
 
+    fn index(&mut self, location: SourceSpan, can_assign: bool) -> Result<()> {
+        self.expression()?;
+        let right_bracket_location = consume!(
+            self.scanner,
+            TokenType::RightBracket,
+            "Expected ']' after index"
+        );
+        let location = location.until(right_bracket_location);
+        if can_assign && match_token!(self.scanner, TokenType::Equal)?.is_some() {
+            self.expression()?;
+            self.current.chunk.write(Op::SetIndex, location);
+        } else if let Some(op) = self.compound_assignment(can_assign)? {
+            self.emit_update(Target::Index, Update::Compound(op), location)?;
+        } else {
+            self.current.chunk.write(Op::Index, location);
+        }
+        Ok(())
+    }
+
     fn dot(&mut self, location: SourceSpan, can_assign: bool) -> Result<()> {
         let (name, _) = self.scanner.consume_identifier("property after .")?;
 
@@ -161,22 +240,155 @@ impl Parser<'_, '_> {
 
         if can_assign && match_token!(self.scanner, TokenType::Equal)?.is_some() {
             self.expression()?;
-            self.current
-                .chunk
-                .write(Op::SetProperty(constant_index), location);
+            let cache = self.current.chunk.add_cache();
+            self.current.chunk.write(
+                Op::SetProperty {
+                    property_index: constant_index,
+                    cache,
+                },
+                location,
+            );
+        } else if let Some(op) = self.compound_assignment(can_assign)? {
+            self.emit_update(
+                Target::Property(constant_index),
+                Update::Compound(op),
+                location,
+            )?;
         } else if match_token!(self.scanner, TokenType::LeftParen)?.is_some() {
             let arg_count = self.argument_list()?;
+            let cache = self.current.chunk.add_cache();
             self.current.chunk.write(
                 Op::Invoke {
                     property_index: constant_index,
                     arg_count,
+                    cache,
                 },
                 location,
             );
         } else {
-            self.current
-                .chunk
-                .write(Op::GetProperty(constant_index), location);
+            let cache = self.current.chunk.add_cache();
+            self.current.chunk.write(
+                Op::GetProperty {
+                    property_index: constant_index,
+                    cache,
+                },
+                location,
+            );
+        }
+        Ok(())
+    }
+
+    pub(super) fn compound_assignment(&mut self, can_assign: bool) -> Result<Option<Op>> {
+        if !can_assign {
+            return Ok(None);
+        }
+        let Some(token) = match_token!(
+            self.scanner,
+            TokenType::PlusEqual
+                | TokenType::MinusEqual
+                | TokenType::StarEqual
+                | TokenType::SlashEqual
+        )?
+        else {
+            return Ok(None);
+        };
+        Ok(Some(match token.token_type {
+            TokenType::PlusEqual => Op::Add,
+            TokenType::MinusEqual => Op::Subtract,
+            TokenType::StarEqual => Op::Multiply,
+            _ => Op::Divide,
+        }))
+    }
+
+    // the operand is compiled as a read, which is replaced by the update
+    fn prefix_update(&mut self, op: Op, location: SourceSpan) -> Result<()> {
+        self.parse_precedence(Precedence::Call)?;
+        let target = self.update_target(location)?;
+        self.emit_update(target, Update::Prefix(op), location)
+    }
+
+    fn postfix_update(&mut self, op: Op, location: SourceSpan) -> Result<()> {
+        let target = self.update_target(location)?;
+        self.emit_update(target, Update::Postfix(op), location)
+    }
+
+    fn update_target(&mut self, location: SourceSpan) -> Result<Target> {
+        let read = self.current.pop_read();
+        match read.filter(|op| !self.current.reads_this(op)) {
+            Some(Op::GetLocal(slot)) => Ok(Target::Variable {
+                get: Op::GetLocal(slot),
+                set: Op::SetLocal(slot),
+            }),
+            Some(Op::GetUpvalue(index)) => Ok(Target::Variable {
+                get: Op::GetUpvalue(index),
+                set: Op::SetUpvalue(index),
+            }),
+            Some(Op::GetGlobal(index)) => Ok(Target::Variable {
+                get: Op::GetGlobal(index),
+                set: Op::SetGlobal(index),
+            }),
+            Some(Op::GetProperty { property_index, .. }) => Ok(Target::Property(property_index)),
+            Some(Op::Index) => Ok(Target::Index),
+            _ => miette::bail!(
+                labels = vec![LabeledSpan::at(location, "here")],
+                "Invalid assignment target",
+            ),
+        }
+    }
+
+    /// Reads the target, combines it with the operand and writes it back. Receivers and indices
+    /// of the target are on the stack and evaluated once, they are duplicated for the read.
+    pub(super) fn emit_update(
+        &mut self,
+        target: Target,
+        update: Update,
+        location: SourceSpan,
+    ) -> Result<()> {
+        let operands = match target {
+            Target::Variable { .. } => 0,
+            Target::Property(_) => 1,
+            Target::Index => 2,
+        };
+        for _ in 0..operands {
+            self.current.chunk.write(Op::Dup(operands - 1), location);
+        }
+        let (get, set) = match target {
+            Target::Variable { get, set } => (get, set),
+            Target::Property(property_index) => (
+                Op::GetProperty {
+                    property_index,
+                    cache: self.current.chunk.add_cache(),
+                },
+                Op::SetProperty {
+                    property_index,
+                    cache: self.current.chunk.add_cache(),
+                },
+            ),
+            Target::Index => (Op::Index, Op::SetIndex),
+        };
+        self.current.chunk.write(get, location);
+        match update {
+            Update::Compound(op) => {
+                self.expression()?;
+                self.current.emit_binary(op, location);
+            }
+            Update::Prefix(op) => {
+                self.current.emit_constant(Value::Number(1.0), location);
+                self.current.chunk.write(op, location);
+            }
+            Update::Postfix(op) => {
+                // the old value is kept below the operands of the write
+                self.current.chunk.write(Op::Dup(0), location);
+                if operands > 0 {
+                    self.current.chunk.write(Op::Bury(operands + 1), location);
+                }
+                self.current.emit_constant(Value::Number(1.0), location);
+                self.current.chunk.write(op, location);
+            }
+        }
+        self.current.chunk.write(set, location);
+        if matches!(update, Update::Postfix(_)) {
+            self.current.chunk.write(Op::Pop, location);
         }
         Ok(())
     }
@@ -189,7 +401,7 @@ impl Parser<'_, '_> {
         location: SourceSpan,
     ) -> Result<()> {
         self.parse_precedence(precedence)?;
-        self.current.chunk.write(op, location);
+        self.current.emit_binary(op, location);
         if let Some(o) = second_op {
             self.current.chunk.write(o, location)
         }
@@ -202,6 +414,47 @@ impl Parser<'_, '_> {
         Ok(())
     }
 
+    // the parts of the literal and the values of the expressions are joined by a single op
+    fn interpolation(&mut self, first: Cow<str>, location: SourceSpan) -> Result<()> {
+        let mut part = first;
+        let mut part_count = 0usize;
+        let end = loop {
+            if !part.is_empty() {
+                let obj = self.gc.alloc(part.as_ref());
+                self.current.emit_constant(Value::Obj(obj), location);
+                part_count += 1;
+            }
+            self.expression()?;
+            part_count += 1;
+            let token = self.scanner.advance()?;
+            match token.token_type {
+                TokenType::Interpolation(next) => part = next,
+                TokenType::String(last) => {
+                    if !last.is_empty() {
+                        let obj = self.gc.alloc(last.as_ref());
+                        self.current.emit_constant(Value::Obj(obj), token.location);
+                        part_count += 1;
+                    }
+                    break token.location;
+                }
+                _ => miette::bail!(
+                    labels = vec![LabeledSpan::at(token.location, "here")],
+                    "Expected '}}' after interpolated expression"
+                ),
+            }
+        };
+        let Ok(part_count) = u8::try_from(part_count) else {
+            miette::bail!(
+                labels = vec![LabeledSpan::at(location.until(end), "here")],
+                "Too many parts in interpolated string"
+            )
+        };
+        self.current
+            .chunk
+            .write(Op::BuildString(part_count), location.until(end));
+        Ok(())
+    }
+
     fn grouping(&mut self) -> Result<()> {
         self.expression()?;
         consume!(
@@ -212,6 +465,39 @@ impl Parser<'_, '_> {
         Ok(())
     }
 
+    fn anonymous_function(&mut self, location: SourceSpan) -> Result<()> {
+        self.init_anonymous_compiler(location);
+        let left_paren_location =
+            consume!(self.scanner, TokenType::LeftParen, "Expected '(' after fun");
+        self.function_body(left_paren_location)
+    }
+
+    // `(a, b) => expression` or `(a, b) => { statements }`
+    fn arrow_function(&mut self, left_paren_location: SourceSpan) -> Result<()> {
+        self.init_anonymous_compiler(left_paren_location);
+        self.parameters(left_paren_location)?;
+        let arrow_location = consume!(self.scanner, TokenType::Arrow, "Expected '=>'");
+        if match_token!(self.scanner, TokenType::LeftBrace)?.is_some() {
+            let closing_location = self.block()?;
+            self.emit_closure(closing_location);
+        } else {
+            self.expression()?;
+            self.current.mark_tail_call();
+            self.current.chunk.write(Op::Return, arrow_location);
+            self.emit_closure(arrow_location);
+        }
+        Ok(())
+    }
+
+    fn init_anonymous_compiler(&mut self, location: SourceSpan) {
+        let line = self
+            .src
+            .read_span(&location, 0, 0)
+            .map_or(0, |contents| contents.line() + 1);
+        self.init_compiler(FunctionType::Function, Some(format!("anonymous@{line}")));
+        self.current.begin_scope(); // has not to be ended because we drop the compiler in the end
+    }
+
     fn and(&mut self, location: SourceSpan) -> Result<()> {
         let end_jump = self.current.emit_jump(Op::JumpIfFalse, location);
         self.current.chunk.write(Op::Pop, location);
@@ -230,6 +516,50 @@ impl Parser<'_, '_> {
         Ok(())
     }
 
+    // the right operand is only evaluated if the left one is nil
+    fn coalesce(&mut self, location: SourceSpan) -> Result<()> {
+        let else_jump = self.current.emit_jump(Op::JumpIfNil, location);
+        let end_jump = self.current.emit_jump(Op::Jump, location);
+        self.current.patch_jump(else_jump)?;
+        self.current.chunk.write(Op::Pop, location);
+        self.parse_precedence(Precedence::Coalesce)?;
+        self.current.patch_jump(end_jump)?;
+        Ok(())
+    }
+
+    // `cond ? a : b`, right associative so `a ? b : c ? d : e` chains
+    fn conditional(&mut self, location: SourceSpan) -> Result<()> {
+        let then_jump = self.current.emit_jump(Op::JumpIfFalse, location);
+        self.current.chunk.write(Op::Pop, location);
+        self.expression()?;
+        consume!(
+            self.scanner,
+            TokenType::Colon,
+            "Expected ':' after then branch of conditional"
+        );
+        let else_jump = self.current.emit_jump(Op::Jump, location);
+        self.current.patch_jump(then_jump)?;
+        self.current.chunk.write(Op::Pop, location);
+        self.parse_precedence(Precedence::Conditional)?;
+        self.current.patch_jump(else_jump)?;
+        Ok(())
+    }
+
+    // a nil receiver is the value, the rest of the chain of accesses and calls is skipped
+    fn optional_dot(&mut self, location: SourceSpan) -> Result<()> {
+        let nil_jump = self.current.emit_jump(Op::JumpIfNil, location);
+        self.dot(location, false)?;
+        while check!(
+            self.scanner,
+            TokenType::Dot | TokenType::QuestionDot | TokenType::LeftParen | TokenType::LeftBracket
+        ) {
+            let token = self.scanner.advance()?;
+            self.infix(token, false)?;
+        }
+        self.current.patch_jump(nil_jump)?;
+        Ok(())
+    }
+
     fn call(&mut self, location: SourceSpan) -> Result<()> {
         let arg_count = self.argument_list()?;
         self.current.chunk.write(Op::Call(arg_count), location);
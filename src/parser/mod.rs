@@ -6,6 +6,8 @@ use std::{mem::replace, sync::Arc};
 use miette::{ByteOffset, Diagnostic, LabeledSpan, NamedSource, Report, Result, SourceSpan};
 use tracing::debug;
 
+use expression::{Target, Update};
+
 use crate::{
     class_compiler::ClassCompiler,
     compiler::{Compiler, FunctionType},
@@ -87,11 +89,14 @@ impl<'a, 'gc> Parser<'a, 'gc> {
                     }
                     TokenType::Class
                     | TokenType::Fun
+                    | TokenType::Import
                     | TokenType::Var
                     | TokenType::For
                     | TokenType::If
                     | TokenType::While
-                    | TokenType::Print => return,
+                    | TokenType::Print
+                    | TokenType::Try
+                    | TokenType::Throw => return,
                     _ => (),
                 }
             }
@@ -99,24 +104,27 @@ impl<'a, 'gc> Parser<'a, 'gc> {
         }
     }
 
-    fn init_compiler(&mut self, function_type: FunctionType) {
-        let name = if let FunctionType::Script = function_type {
-            None
-        } else {
-            Some(self.scanner.previous_lexeme())
-        };
+    fn init_compiler(&mut self, function_type: FunctionType, name: Option<String>) {
         let new_compiler = Compiler::new(function_type, name, self.src.clone());
         let old_compiler = replace(&mut self.current, new_compiler);
         self.current.enclosing = Some(Box::new(old_compiler));
     }
 
     fn emit_return(&mut self, location: SourceSpan) {
-        if self.current.function_type == FunctionType::Initializer {
+        self.emit_default_return_value(location);
+        self.current.chunk.write(Op::Return, location);
+    }
+
+    // a script returns its module from slot zero, which is what an import pushes
+    fn emit_default_return_value(&mut self, location: SourceSpan) {
+        if matches!(
+            self.current.function_type,
+            FunctionType::Initializer | FunctionType::Script
+        ) {
             self.current.chunk.write(Op::GetLocal(0), location);
         } else {
             self.current.chunk.write(Op::Nil, location);
         }
-        self.current.chunk.write(Op::Return, location);
     }
 
     pub fn end_compiler(&mut self, location: SourceSpan) -> Function {
@@ -159,6 +167,12 @@ impl<'a, 'gc> Parser<'a, 'gc> {
         if can_assign && match_token!(self.scanner, TokenType::Equal)?.is_some() {
             self.expression()?;
             self.current.chunk.write(set_op, location);
+        } else if let Some(op) = self.compound_assignment(can_assign)? {
+            let target = Target::Variable {
+                get: get_op,
+                set: set_op,
+            };
+            self.emit_update(target, Update::Compound(op), location)?;
         } else {
             self.current.chunk.write(get_op, location);
         }
@@ -3,8 +3,9 @@ use miette::{LabeledSpan, SourceSpan};
 use super::{Parser, Result};
 use crate::{
     check,
+    chunk::ExceptionHandler,
     class_compiler::ClassCompiler,
-    compiler::FunctionType,
+    compiler::{FunctionType, Jump, TryContext},
     consume, match_token,
     op::Op,
     source_span_extensions::SourceSpanExtensions,
@@ -20,6 +21,8 @@ impl Parser<'_, '_> {
             self.fun_declaration(fun_token.location)
         } else if let Ok(Some(var_token)) = match_token!(self.scanner, TokenType::Var) {
             self.var_declaration(var_token.location)
+        } else if let Ok(Some(import_token)) = match_token!(self.scanner, TokenType::Import) {
+            self.import_declaration(import_token.location)
         } else {
             self.statement()
         };
@@ -70,7 +73,11 @@ impl Parser<'_, '_> {
             "Expected '{{' before class body"
         );
         while (!check!(self.scanner, TokenType::RightBrace)) && self.scanner.peek().is_some() {
-            self.method()?;
+            if let Some(class_token) = match_token!(self.scanner, TokenType::Class)? {
+                self.static_member(class_token.location)?;
+            } else {
+                self.method()?;
+            }
         }
 
         let location = consume!(
@@ -109,6 +116,44 @@ impl Parser<'_, '_> {
         Ok(())
     }
 
+    // `class name() {}` is called on the class, `class var name = value;` is a field of it
+    fn static_member(&mut self, location: SourceSpan) -> Result<()> {
+        if match_token!(self.scanner, TokenType::Var)?.is_some() {
+            let (identifier, _) = self.scanner.consume_identifier("static field name")?;
+            let constant = self.current.identifier_constant(self.gc.alloc(identifier));
+            // the class below stays for the following members
+            self.current.chunk.write(Op::Dup(0), location);
+            if match_token!(self.scanner, TokenType::Equal)?.is_some() {
+                self.expression()?;
+            } else {
+                self.current.chunk.write(Op::Nil, location);
+            }
+            let semicolon_location = consume!(
+                self,
+                TokenType::Semicolon,
+                "Expected ';' after static field"
+            );
+            let cache = self.current.chunk.add_cache();
+            let location = location.until(semicolon_location);
+            self.current.chunk.write(
+                Op::SetProperty {
+                    property_index: constant,
+                    cache,
+                },
+                location,
+            );
+            self.current.chunk.write(Op::Pop, location);
+            return Ok(());
+        }
+        let (identifier, location) = self.scanner.consume_identifier("static method name")?;
+        let constant = self.current.identifier_constant(self.gc.alloc(identifier));
+        self.function(FunctionType::Method)?;
+        self.current
+            .chunk
+            .write(Op::StaticMethod(constant), location);
+        Ok(())
+    }
+
     fn var_declaration(&mut self, location: SourceSpan) -> Result<()> {
         let global = self.parse_variable()?;
         if (match_token!(self.scanner, TokenType::Equal)?).is_some() {
@@ -122,6 +167,87 @@ impl Parser<'_, '_> {
         Ok(())
     }
 
+    /// `import "path" as name;` binds the module, `import { a, b } from "path";` binds variables
+    /// of the module and `import "path";` only runs it.
+    fn import_declaration(&mut self, location: SourceSpan) -> Result<()> {
+        if (match_token!(self.scanner, TokenType::LeftBrace)?).is_some() {
+            let mut names = vec![];
+            loop {
+                names.push(self.scanner.consume_identifier("imported variable name")?);
+                if (match_token!(self.scanner, TokenType::Comma)?).is_none() {
+                    break;
+                }
+            }
+            consume!(
+                self,
+                TokenType::RightBrace,
+                "Expected '}}' after imported names"
+            );
+            self.contextual_keyword("from")?;
+            let path = self.import_path()?;
+            let semicolon_location =
+                consume!(self, TokenType::Semicolon, "Expected ';' after import");
+            for (name, name_location) in names {
+                let name_idx = self.current.identifier_constant(self.gc.alloc(name));
+                self.current.declare_variable(name, name_location)?;
+                self.current.chunk.write(Op::Import(path), location);
+                let cache = self.current.chunk.add_cache();
+                self.current.chunk.write(
+                    Op::GetProperty {
+                        property_index: name_idx,
+                        cache,
+                    },
+                    name_location,
+                );
+                let global = (!self.current.is_local()).then_some(name_idx);
+                self.current
+                    .define_variable(global, location.until(semicolon_location));
+            }
+            return Ok(());
+        }
+
+        let path = self.import_path()?;
+        if check!(self.scanner, TokenType::Identifier("as")) {
+            self.contextual_keyword("as")?;
+            let global = self.parse_variable()?;
+            self.current.chunk.write(Op::Import(path), location);
+            let semicolon_location =
+                consume!(self, TokenType::Semicolon, "Expected ';' after import");
+            self.current
+                .define_variable(global, location.until(semicolon_location));
+        } else {
+            self.current.chunk.write(Op::Import(path), location);
+            consume!(self, TokenType::Semicolon, "Expected ';' after import");
+            self.current.chunk.write(Op::Pop, location);
+        }
+        Ok(())
+    }
+
+    fn import_path(&mut self) -> Result<u8> {
+        let token = self.advance()?;
+        if let TokenType::String(path) = token.token_type {
+            Ok(self
+                .current
+                .identifier_constant(self.gc.alloc(path.as_ref())))
+        } else {
+            miette::bail!(
+                labels = vec![LabeledSpan::at(token.location, "here")],
+                "Expected a path string to import"
+            )
+        }
+    }
+
+    fn contextual_keyword(&mut self, keyword: &str) -> Result<()> {
+        let (id, location) = self.scanner.consume_identifier(keyword)?;
+        if id != keyword {
+            miette::bail!(
+                labels = vec![LabeledSpan::at(location, "here")],
+                "Expected '{keyword}' in import"
+            )
+        }
+        Ok(())
+    }
+
     fn fun_declaration(&mut self, location: SourceSpan) -> Result<()> {
         let global = self.parse_variable()?;
         self.current.mark_latest_initialized();
@@ -141,7 +267,7 @@ impl Parser<'_, '_> {
     }
 
     fn function(&mut self, function_type: FunctionType) -> Result<()> {
-        self.init_compiler(function_type);
+        self.init_compiler(function_type, Some(self.scanner.previous_lexeme()));
         self.current.begin_scope(); // has not to be ended because we drop the compiler in the end
 
         let left_paren_location = consume!(
@@ -149,6 +275,24 @@ impl Parser<'_, '_> {
             TokenType::LeftParen,
             "Expected '(' after function name"
         );
+        self.function_body(left_paren_location)
+    }
+
+    // everything after the opening paren of the parameter list
+    pub(super) fn function_body(&mut self, left_paren_location: SourceSpan) -> Result<()> {
+        self.parameters(left_paren_location)?;
+        consume!(
+            self,
+            TokenType::LeftBrace,
+            "Expected '{{' before function body"
+        );
+
+        let closing_location = self.block()?;
+        self.emit_closure(closing_location);
+        Ok(())
+    }
+
+    pub(super) fn parameters(&mut self, left_paren_location: SourceSpan) -> Result<()> {
         if !check!(self.scanner, TokenType::RightParen) {
             loop {
                 self.current.arity = if let Some(arity) = self.current.arity.checked_add(1) {
@@ -167,18 +311,14 @@ impl Parser<'_, '_> {
             }
         }
         consume!(self, TokenType::RightParen, "Expected ')' after parameters");
-        consume!(
-            self,
-            TokenType::LeftBrace,
-            "Expected '{{' before function body"
-        );
+        Ok(())
+    }
 
-        let closing_location = self.block()?;
-        let function = self.end_compiler(closing_location);
+    pub(super) fn emit_closure(&mut self, location: SourceSpan) {
+        let function = self.end_compiler(location);
         let obj_ref = self.gc.alloc(Obj::Function(function));
         let idx = self.current.chunk.add_constant(Value::Obj(obj_ref));
-        self.current.chunk.write(Op::Closure(idx), closing_location);
-        Ok(())
+        self.current.chunk.write(Op::Closure(idx), location);
     }
 
     fn statement(&mut self) -> Result<()> {
@@ -192,6 +332,10 @@ impl Parser<'_, '_> {
             self.return_statement(return_token.location)
         } else if let Some(while_token) = match_token!(self.scanner, TokenType::While)? {
             self.while_statement(while_token.location)
+        } else if let Some(throw_token) = match_token!(self.scanner, TokenType::Throw)? {
+            self.throw_statement(throw_token.location)
+        } else if let Some(try_token) = match_token!(self.scanner, TokenType::Try)? {
+            self.try_statement(try_token.location)
         } else if (match_token!(self.scanner, TokenType::LeftBrace)?).is_some() {
             self.current.begin_scope();
             let closing_location = self.block()?;
@@ -202,6 +346,161 @@ impl Parser<'_, '_> {
         }
     }
 
+    fn throw_statement(&mut self, location: SourceSpan) -> Result<()> {
+        self.expression()?;
+        consume!(
+            self,
+            TokenType::Semicolon,
+            "Expected ';' after thrown value"
+        );
+        self.current.chunk.write(Op::Throw, location);
+        Ok(())
+    }
+
+    // try { A } catch (e) { B } finally { C } compiles to
+    //      Nil, Nil               hidden locals of the TryContext
+    //      A                      caught by catch_target, and by finally_catch if there is a finally
+    //      Jump finally
+    //   catch_target:
+    //      B                      caught by finally_catch
+    //      Jump finally
+    //   finally_catch:
+    //      store the exception to rethrow
+    //   finally:
+    //      C
+    //      EndFinally             rethrows, falls through or skips the jump to return
+    //      Jump end
+    //      return, through the finally of an enclosing try statement if there is one
+    //   end:
+    fn try_statement(&mut self, location: SourceSpan) -> Result<()> {
+        self.current.begin_scope();
+        for _ in 0..2 {
+            self.current.chunk.write(Op::Nil, location);
+            self.current.add_local("", location)?;
+            self.current.mark_latest_initialized();
+        }
+        let stack_depth = self.current.local_count();
+        let kind_slot = (stack_depth - 2) as u8;
+        self.current.tries.push(TryContext {
+            kind_slot,
+            stack_depth,
+            finally_jumps: vec![],
+        });
+
+        let try_start = self.current.chunk.code.len();
+        consume!(self, TokenType::LeftBrace, "Expected '{{' after try");
+        self.current.begin_scope();
+        let closing_location = self.block()?;
+        self.current.end_scope(closing_location);
+        let try_end = self.current.chunk.code.len();
+        let try_jump = self.current.emit_jump(Op::Jump, closing_location);
+        self.add_finally_jump(try_jump);
+
+        let catch_token = match_token!(self.scanner, TokenType::Catch)?;
+        if let Some(catch_token) = &catch_token {
+            let target = self.current.handler_target();
+            self.current.chunk.add_handler(ExceptionHandler {
+                start: try_start,
+                end: try_end,
+                target,
+                stack_depth,
+            });
+            self.current.begin_scope();
+            if match_token!(self.scanner, TokenType::LeftParen)?.is_some() {
+                let (name, name_location) = self.scanner.consume_identifier("exception name")?;
+                self.current.add_local(name, name_location)?;
+                self.current.mark_latest_initialized();
+                consume!(
+                    self,
+                    TokenType::RightParen,
+                    "Expected ')' after exception name"
+                );
+            } else {
+                self.current.chunk.write(Op::Pop, catch_token.location);
+            }
+            consume!(self, TokenType::LeftBrace, "Expected '{{' after catch");
+            let closing_location = self.block()?;
+            self.current.end_scope(closing_location);
+            let catch_jump = self.current.emit_jump(Op::Jump, closing_location);
+            self.add_finally_jump(catch_jump);
+        }
+
+        let finally_token = match_token!(self.scanner, TokenType::Finally)?;
+        if let Some(finally_token) = &finally_token {
+            let target = self.current.handler_target();
+            self.current.chunk.add_handler(ExceptionHandler {
+                start: try_start,
+                end: target,
+                target,
+                stack_depth,
+            });
+            self.emit_finally_entry(kind_slot, true, finally_token.location);
+        } else if catch_token.is_none() {
+            miette::bail!(
+                labels = vec![LabeledSpan::at(location, "here")],
+                "Expected catch or finally after try block",
+            );
+        }
+
+        let context = self.current.tries.pop().expect("pushed above");
+        for jump in context.finally_jumps {
+            self.current.patch_jump(jump)?;
+        }
+        let finally_location = if let Some(finally_token) = finally_token {
+            consume!(self, TokenType::LeftBrace, "Expected '{{' after finally");
+            self.current.begin_scope();
+            let closing_location = self.block()?;
+            self.current.end_scope(closing_location);
+            finally_token.location
+        } else {
+            location
+        };
+        self.current
+            .chunk
+            .write(Op::EndFinally(kind_slot), finally_location);
+        let end_jump = self.current.emit_jump(Op::Jump, finally_location);
+        if self.current.tries.is_empty() {
+            self.current.chunk.write(Op::Return, finally_location);
+        } else {
+            self.emit_return_through_finally(finally_location);
+        }
+        self.current.patch_jump(end_jump)?;
+        self.current.end_scope(finally_location);
+        Ok(())
+    }
+
+    fn add_finally_jump(&mut self, jump: Jump) {
+        self.current
+            .tries
+            .last_mut()
+            .expect("inside a try statement")
+            .finally_jumps
+            .push(jump);
+    }
+
+    // stores the value on top of the stack in the hidden locals of the innermost try statement
+    fn emit_finally_entry(&mut self, kind_slot: u8, exception: bool, location: SourceSpan) {
+        self.current
+            .chunk
+            .write(Op::SetLocal(kind_slot + 1), location);
+        self.current.chunk.write(Op::Pop, location);
+        self.current
+            .chunk
+            .write(if exception { Op::True } else { Op::False }, location);
+        self.current.chunk.write(Op::SetLocal(kind_slot), location);
+        self.current.chunk.write(Op::Pop, location);
+    }
+
+    // the returned value is on top of the stack
+    fn emit_return_through_finally(&mut self, location: SourceSpan) {
+        let context = self.current.tries.last().expect("inside a try statement");
+        let (kind_slot, stack_depth) = (context.kind_slot, context.stack_depth);
+        self.emit_finally_entry(kind_slot, false, location);
+        self.current.emit_discard_locals(stack_depth, location);
+        let jump = self.current.emit_jump(Op::Jump, location);
+        self.add_finally_jump(jump);
+    }
+
     fn print_statement(&mut self, location: SourceSpan) -> Result<()> {
         self.expression()?;
         consume!(self, TokenType::Semicolon, "Expected ';' after value");
@@ -217,15 +516,13 @@ impl Parser<'_, '_> {
             consume!(self, TokenType::RightParen, "Expected ')' after condition");
 
         let location = location.until(right_paren_location);
-        let then_jump = self.current.emit_jump(Op::JumpIfFalse, location);
-        self.current.chunk.write(Op::Pop, location);
+        let then_jump = self.current.emit_condition_jump(location);
 
         self.statement()?;
 
         let else_jump = self.current.emit_jump(Op::Jump, location);
 
-        self.current.patch_jump(then_jump)?;
-        self.current.chunk.write(Op::Pop, location);
+        self.current.patch_condition_jump(then_jump)?;
 
         if match_token!(self.scanner, TokenType::Else)?.is_some() {
             self.statement()?;
@@ -243,7 +540,12 @@ impl Parser<'_, '_> {
             )
         }
         if match_token!(self.scanner, TokenType::Semicolon)?.is_some() {
-            self.emit_return(location);
+            if self.current.tries.is_empty() {
+                self.emit_return(location);
+            } else {
+                self.emit_default_return_value(location);
+                self.emit_return_through_finally(location);
+            }
         } else {
             if self.current.function_type == FunctionType::Initializer {
                 miette::bail!(
@@ -257,28 +559,31 @@ impl Parser<'_, '_> {
                 TokenType::Semicolon,
                 "Expected ';' after return value"
             );
-            self.current.chunk.write(Op::Return, location);
+            if self.current.tries.is_empty() {
+                self.current.mark_tail_call();
+                self.current.chunk.write(Op::Return, location);
+            } else {
+                self.emit_return_through_finally(location);
+            }
         }
         Ok(())
     }
 
     fn while_statement(&mut self, location: SourceSpan) -> Result<()> {
-        let loop_start = self.current.chunk.code.len();
+        let loop_start = self.current.loop_start();
         consume!(self, TokenType::LeftParen, "Expected '(' after while");
         self.expression()?;
         let right_paren_location =
             consume!(self, TokenType::RightParen, "Expected ')' after condition");
         let location = location.until(right_paren_location);
 
-        let exit_jump = self.current.emit_jump(Op::JumpIfFalse, location);
-        self.current.chunk.write(Op::Pop, location);
+        let exit_jump = self.current.emit_condition_jump(location);
 
         self.statement()?;
 
         self.current.emit_loop(loop_start, location)?;
 
-        self.current.patch_jump(exit_jump)?;
-        self.current.chunk.write(Op::Pop, location);
+        self.current.patch_condition_jump(exit_jump)?;
         Ok(())
     }
 
@@ -293,7 +598,7 @@ impl Parser<'_, '_> {
             self.expression_statement()?;
         }
 
-        let mut loop_start = self.current.chunk.code.len();
+        let mut loop_start = self.current.loop_start();
         let mut exit_jump = None;
 
         if match_token!(self.scanner, TokenType::Semicolon)?.is_none() {
@@ -303,12 +608,11 @@ impl Parser<'_, '_> {
                 TokenType::Semicolon,
                 "Expected ';' after loop condition"
             );
-            exit_jump = Some(self.current.emit_jump(Op::JumpIfFalse, semicolon_location));
-            self.current.chunk.write(Op::Pop, semicolon_location);
+            exit_jump = Some(self.current.emit_condition_jump(semicolon_location));
         }
         if match_token!(self.scanner, TokenType::RightParen)?.is_none() {
             let body_jump = self.current.emit_jump(Op::Jump, location);
-            let increment_start = self.current.chunk.code.len();
+            let increment_start = self.current.loop_start();
             self.expression()?;
             self.current.chunk.write(Op::Pop, location);
             consume!(
@@ -325,8 +629,7 @@ impl Parser<'_, '_> {
 
         self.current.emit_loop(loop_start, location)?;
         if let Some(exit_jump) = exit_jump {
-            self.current.patch_jump(exit_jump)?;
-            self.current.chunk.write(Op::Pop, location);
+            self.current.patch_condition_jump(exit_jump)?;
         }
         self.current.end_scope(location);
         Ok(())
@@ -339,7 +642,7 @@ impl Parser<'_, '_> {
         Ok(())
     }
 
-    fn block(&mut self) -> Result<SourceSpan> {
+    pub(super) fn block(&mut self) -> Result<SourceSpan> {
         while !matches!(
             self.scanner.peek(),
             Some(Ok(Token {
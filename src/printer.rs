@@ -19,9 +19,10 @@ pub mod vec_printer {
 
     use super::Printer;
 
+    // printed values are no gc roots, so they are rendered right away
     #[derive(Clone)]
     pub struct VecPrinter {
-        lines: Rc<RefCell<Vec<Value>>>,
+        lines: Rc<RefCell<Vec<String>>>,
     }
 
     impl VecPrinter {
@@ -35,14 +36,14 @@ pub mod vec_printer {
             self.lines
                 .borrow()
                 .iter()
-                .map(|x| x.to_string().add("\n"))
+                .map(|x| x.clone().add("\n"))
                 .collect()
         }
     }
 
     impl Printer for VecPrinter {
         fn print(&self, value: Value) {
-            self.lines.borrow_mut().push(value)
+            self.lines.borrow_mut().push(value.to_string())
         }
     }
 }
@@ -1,3 +1,5 @@
+use std::borrow::Cow;
+
 use crate::token::{Token, TokenType};
 
 use miette::{Diagnostic, LabeledSpan, NamedSource, Result, SourceSpan};
@@ -20,6 +22,23 @@ pub enum ScannerError {
         #[label("here")]
         location: SourceSpan,
     },
+
+    #[error("Invalid escape sequence: \\{char}")]
+    InvalidEscape {
+        char: char,
+        #[source_code]
+        src: NamedSource<String>,
+        #[label("here")]
+        location: SourceSpan,
+    },
+
+    #[error("Invalid unicode escape, expected \\u{{...}} with 1 to 6 hex digits of a char")]
+    InvalidUnicodeEscape {
+        #[source_code]
+        src: NamedSource<String>,
+        #[label("here")]
+        location: SourceSpan,
+    },
 }
 #[macro_export]
 macro_rules! consume {
@@ -71,6 +90,8 @@ pub struct Scanner<'a> {
     start: usize,
     at: usize,
     peeked: Option<Result<Token<'a>>>,
+    // the braces opened inside each interpolation that is scanned, its `}` continues the string
+    interpolations: Vec<usize>,
 }
 
 impl<'a> Scanner<'a> {
@@ -81,6 +102,7 @@ impl<'a> Scanner<'a> {
             start: 0,
             at: 0,
             peeked: None,
+            interpolations: vec![],
         }
     }
 
@@ -108,6 +130,36 @@ impl<'a> Scanner<'a> {
         self.src.inner().as_str()[self.start..self.at].to_string()
     }
 
+    /// Whether the tokens after an opening `(` are a parameter list followed by `=>`,
+    /// scanned on a copy so nothing is consumed.
+    pub fn is_arrow_parameter_list(&mut self) -> bool {
+        let first = match self.peek() {
+            Some(Ok(token)) => token.token_type.clone(),
+            _ => return false,
+        };
+        let mut lookahead = Scanner {
+            src: self.src,
+            rest: self.rest,
+            start: self.start,
+            at: self.at,
+            peeked: None,
+            interpolations: self.interpolations.clone(),
+        };
+        let mut tokens = std::iter::once(first).chain(std::iter::from_fn(|| {
+            lookahead.next()?.ok().map(|token| token.token_type)
+        }));
+        let mut expect_parameter = true;
+        loop {
+            match (tokens.next(), expect_parameter) {
+                (Some(TokenType::RightParen), _) => break,
+                (Some(TokenType::Identifier(_)), true) => expect_parameter = false,
+                (Some(TokenType::Comma), false) => expect_parameter = true,
+                _ => return false,
+            }
+        }
+        tokens.next() == Some(TokenType::Arrow)
+    }
+
     pub fn consume_identifier(&mut self, expected: &str) -> Result<(&'a str, SourceSpan)> {
         let next = self.advance()?;
         if let TokenType::Identifier(id) = next.token_type {
@@ -177,10 +229,65 @@ impl<'a> Scanner<'a> {
     }
 
     fn read_string(&mut self) -> Result<TokenType<'a>> {
+        if self.rest.starts_with("\"\"") {
+            self.read_raw_string()
+        } else {
+            self.read_string_part()
+        }
+    }
+
+    // reads after a `"` or the `}` of an interpolation up to the closing quote or the next `${`.
+    // The lexeme is borrowed from the source unless it has escapes, after an invalid escape
+    // the rest of the string is still consumed, so scanning continues behind it
+    fn read_string_part(&mut self) -> Result<TokenType<'a>> {
+        let mut escaped: Option<String> = None;
+        let mut error = None;
         loop {
             match self.inner_peek() {
                 Some('"') => break,
-                Some(_) => {
+                Some('$') if self.peek_next() == Some('{') => {
+                    self.inner_advance();
+                    self.inner_advance();
+                    self.interpolations.push(0);
+                    if let Some(error) = error {
+                        Err(error)?
+                    }
+                    let end = self.at - 2;
+                    return Ok(TokenType::Interpolation(self.lexeme(escaped, end)));
+                }
+                Some('\\') => {
+                    let escape_start = self.at;
+                    let unescaped = escaped.get_or_insert_with(|| {
+                        self.src.inner()[self.start + 1..escape_start].to_string()
+                    });
+                    self.inner_advance();
+                    let char = match self.inner_advance() {
+                        Some('n') => Ok('\n'),
+                        Some('t') => Ok('\t'),
+                        Some('r') => Ok('\r'),
+                        Some('0') => Ok('\0'),
+                        Some('\\') => Ok('\\'),
+                        Some('"') => Ok('"'),
+                        Some('$') => Ok('$'),
+                        Some('u') => self.read_unicode_escape(escape_start),
+                        Some(char) => Err(ScannerError::InvalidEscape {
+                            char,
+                            src: self.src.clone(),
+                            location: SourceSpan::from(escape_start..self.at),
+                        }),
+                        None => continue,
+                    };
+                    match char {
+                        Ok(char) => unescaped.push(char),
+                        Err(err) => {
+                            error.get_or_insert(err);
+                        }
+                    }
+                }
+                Some(char) => {
+                    if let Some(unescaped) = &mut escaped {
+                        unescaped.push(char);
+                    }
                     self.inner_advance();
                 }
                 None => Err(ScannerError::NonTerminatedString {
@@ -190,8 +297,61 @@ impl<'a> Scanner<'a> {
             }
         }
         self.inner_advance();
-        let string = &self.src.inner()[self.start + 1..self.at - 1];
-        Ok(TokenType::String(string))
+        if let Some(error) = error {
+            Err(error)?
+        }
+        Ok(TokenType::String(self.lexeme(escaped, self.at - 1)))
+    }
+
+    fn lexeme(&self, escaped: Option<String>, end: usize) -> Cow<'a, str> {
+        match escaped {
+            Some(string) => Cow::Owned(string),
+            None => Cow::Borrowed(&self.src.inner()[self.start + 1..end]),
+        }
+    }
+
+    // `\u{1F600}`, the backslash and u are consumed
+    fn read_unicode_escape(
+        &mut self,
+        escape_start: usize,
+    ) -> std::result::Result<char, ScannerError> {
+        let invalid = |scanner: &Self| ScannerError::InvalidUnicodeEscape {
+            src: scanner.src.clone(),
+            location: SourceSpan::from(escape_start..scanner.at),
+        };
+        if !self.matches('{') {
+            return Err(invalid(self));
+        }
+        let digits_start = self.at;
+        while self.inner_peek().is_some_and(|c| c.is_ascii_hexdigit()) {
+            self.inner_advance();
+        }
+        let digits = &self.src.inner()[digits_start..self.at];
+        if !self.matches('}') || digits.is_empty() || digits.len() > 6 {
+            return Err(invalid(self));
+        }
+        u32::from_str_radix(digits, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| invalid(self))
+    }
+
+    // `"""` strings take everything up to the next `"""` verbatim, newlines included
+    fn read_raw_string(&mut self) -> Result<TokenType<'a>> {
+        self.inner_advance();
+        self.inner_advance();
+        let Some(end) = self.rest.find("\"\"\"") else {
+            self.at = self.src.inner().len();
+            self.rest = "";
+            Err(ScannerError::NonTerminatedString {
+                src: self.src.clone(),
+                location: SourceSpan::from(self.start..self.at),
+            })?
+        };
+        let string = &self.rest[..end];
+        self.at += end + 3;
+        self.rest = &self.rest[end + 3..];
+        Ok(TokenType::String(Cow::Borrowed(string)))
     }
 
     fn read_number(&mut self) -> TokenType<'a> {
@@ -230,9 +390,17 @@ impl<'a> Scanner<'a> {
 
         match next {
             'a' if iter.as_str() == "nd" => TokenType::And,
-            'c' if iter.as_str() == "lass" => TokenType::Class,
+            'c' => match iter.next() {
+                Some('a') if iter.as_str() == "tch" => TokenType::Catch,
+                Some('l') if iter.as_str() == "ass" => TokenType::Class,
+                _ => TokenType::Identifier(text),
+            },
             'e' if iter.as_str() == "lse" => TokenType::Else,
-            'i' if iter.as_str() == "f" => TokenType::If,
+            'i' => match iter.next() {
+                Some('f') if iter.as_str().is_empty() => TokenType::If,
+                Some('m') if iter.as_str() == "port" => TokenType::Import,
+                _ => TokenType::Identifier(text),
+            },
             'n' if iter.as_str() == "il" => TokenType::Nil,
             'o' if iter.as_str() == "r" => TokenType::Or,
             'p' if iter.as_str() == "rint" => TokenType::Print,
@@ -242,13 +410,16 @@ impl<'a> Scanner<'a> {
             'w' if iter.as_str() == "hile" => TokenType::While,
             'f' => match iter.next() {
                 Some('a') if iter.as_str() == "lse" => TokenType::False,
+                Some('i') if iter.as_str() == "nally" => TokenType::Finally,
                 Some('o') if iter.as_str() == "r" => TokenType::For,
                 Some('u') if iter.as_str() == "n" => TokenType::Fun,
                 _ => TokenType::Identifier(text),
             },
             't' => match iter.next() {
                 Some('h') if iter.as_str() == "is" => TokenType::This,
+                Some('h') if iter.as_str() == "row" => TokenType::Throw,
                 Some('r') if iter.as_str() == "ue" => TokenType::True,
+                Some('r') if iter.as_str() == "y" => TokenType::Try,
                 _ => TokenType::Identifier(text),
             },
             _ => TokenType::Identifier(text),
@@ -271,14 +442,72 @@ impl<'a> Iterator for Scanner<'a> {
         let token_type = match char {
             '(' => LeftParen,
             ')' => RightParen,
-            '{' => LeftBrace,
-            '}' => RightBrace,
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                LeftBrace
+            }
+            '}' if self.interpolations.last() == Some(&0) => {
+                self.interpolations.pop();
+                match self.read_string_part() {
+                    Ok(s) => s,
+                    Err(err) => return Some(Err(err)),
+                }
+            }
+            '}' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth -= 1;
+                }
+                RightBrace
+            }
+            '[' => LeftBracket,
+            ']' => RightBracket,
             ',' => Comma,
             '.' => Dot,
-            '-' => Minus,
-            '+' => Plus,
+            '-' => {
+                if self.matches('=') {
+                    MinusEqual
+                } else if self.matches('-') {
+                    MinusMinus
+                } else {
+                    Minus
+                }
+            }
+            '+' => {
+                if self.matches('=') {
+                    PlusEqual
+                } else if self.matches('+') {
+                    PlusPlus
+                } else {
+                    Plus
+                }
+            }
             ';' => Semicolon,
-            '*' => Star,
+            '*' => {
+                if self.matches('*') {
+                    StarStar
+                } else if self.matches('=') {
+                    StarEqual
+                } else {
+                    Star
+                }
+            }
+            '%' => Percent,
+            '&' => Ampersand,
+            '|' => Pipe,
+            '^' => Caret,
+            '~' if self.matches('/') => TildeSlash,
+            ':' => Colon,
+            '?' => {
+                if self.matches('?') {
+                    QuestionQuestion
+                } else if self.matches('.') {
+                    QuestionDot
+                } else {
+                    Question
+                }
+            }
             '!' => {
                 if self.matches('=') {
                     BangEqual
@@ -289,6 +518,8 @@ impl<'a> Iterator for Scanner<'a> {
             '=' => {
                 if self.matches('=') {
                     EqualEqual
+                } else if self.matches('>') {
+                    Arrow
                 } else {
                     Equal
                 }
@@ -296,6 +527,8 @@ impl<'a> Iterator for Scanner<'a> {
             '<' => {
                 if self.matches('=') {
                     LessEqual
+                } else if self.matches('<') {
+                    LessLess
                 } else {
                     Less
                 }
@@ -303,11 +536,19 @@ impl<'a> Iterator for Scanner<'a> {
             '>' => {
                 if self.matches('=') {
                     GreaterEqual
+                } else if self.matches('>') {
+                    GreaterGreater
                 } else {
                     Greater
                 }
             }
-            '/' => Slash,
+            '/' => {
+                if self.matches('=') {
+                    SlashEqual
+                } else {
+                    Slash
+                }
+            }
             '"' => match self.read_string() {
                 Ok(s) => s,
                 Err(err) => return Some(Err(err)),
@@ -350,17 +591,31 @@ mod tests {
         let result = result.unwrap();
         let expected = vec![
             Token {
-                token_type: Plus,
-                location: SourceSpan::from(0..1),
+                token_type: PlusEqual,
+                location: SourceSpan::from(0..2),
             },
             Token {
-                token_type: EqualEqual,
-                location: SourceSpan::from(1..3),
+                token_type: Equal,
+                location: SourceSpan::from(2..3),
             },
         ];
         assert_eq!(result, expected)
     }
 
+    #[test]
+    fn arrow_parameter_list_is_detected_without_consuming() {
+        let src = NamedSource::new("", "(a, b) => a".to_string());
+        let mut scanner = Scanner::new(&src);
+        scanner.next();
+        assert!(scanner.is_arrow_parameter_list());
+        assert_eq!(scanner.next().unwrap().unwrap().token_type, Identifier("a"));
+
+        let src = NamedSource::new("", "(a + b)".to_string());
+        let mut scanner = Scanner::new(&src);
+        scanner.next();
+        assert!(!scanner.is_arrow_parameter_list());
+    }
+
     #[test]
     fn skip_whitespace() {
         let src = NamedSource::new("", "   \t\n+".to_string());
@@ -381,12 +636,55 @@ mod tests {
         let result: Result<Vec<Token>> = scanner.collect();
         let result = result.unwrap();
         let expected = vec![Token {
-            token_type: String("string"),
+            token_type: String("string".into()),
             location: SourceSpan::from(0..8),
         }];
         assert_eq!(result, expected)
     }
 
+    #[test]
+    fn scan_string_with_escapes() {
+        let src = NamedSource::new("", r#""a\tb\n\"c\" \\ \u{48}\u{1F600}""#.to_string());
+        let scanner = Scanner::new(&src);
+        let result: Result<Vec<Token>> = scanner.collect();
+        let result = result.unwrap();
+        assert_eq!(
+            result[0].token_type,
+            String("a\tb\n\"c\" \\ H\u{1F600}".into())
+        );
+        assert_eq!(result[0].location, SourceSpan::from(0..src.inner().len()));
+    }
+
+    #[test]
+    fn scan_interpolation() {
+        let src = NamedSource::new("", r#""a${ {b} }c${"${d}"}""#.to_string());
+        let scanner = Scanner::new(&src);
+        let result: Result<Vec<Token>> = scanner.collect();
+        let token_types: Vec<_> = result.unwrap().into_iter().map(|t| t.token_type).collect();
+        let expected = vec![
+            Interpolation("a".into()),
+            LeftBrace,
+            Identifier("b"),
+            RightBrace,
+            Interpolation("c".into()),
+            Interpolation("".into()),
+            Identifier("d"),
+            String("".into()),
+            String("".into()),
+        ];
+        assert_eq!(token_types, expected)
+    }
+
+    #[test]
+    fn scan_raw_string() {
+        let src = NamedSource::new("", "\"\"\"a \\n \"b\"\nc\"\"\" +".to_string());
+        let scanner = Scanner::new(&src);
+        let result: Result<Vec<Token>> = scanner.collect();
+        let result = result.unwrap();
+        assert_eq!(result[0].token_type, String("a \\n \"b\"\nc".into()));
+        assert_eq!(result[1].token_type, Plus);
+    }
+
     #[test]
     fn scan_identifier() {
         let src = NamedSource::new("", "string".to_string());
@@ -437,12 +735,12 @@ mod tests {
 
     #[test]
     fn raise_error_on_unexpected_char() {
-        let src = NamedSource::new("", "^".to_string());
+        let src = NamedSource::new("", "#".to_string());
         let scanner = Scanner::new(&src);
         let result: Result<Vec<_>> = scanner.collect();
         let error = result.unwrap_err().downcast().unwrap();
         assert_matches!(error, ScannerError::UnexpectedCharacter {
-             char: '^',
+             char: '#',
              src,
              location,
          } if src.name() == "" && location == SourceSpan::from(0..1))
@@ -459,4 +757,33 @@ mod tests {
              location,
          } if location == SourceSpan::from(0..13))
     }
+
+    #[test]
+    fn raise_error_on_invalid_escape() {
+        let src = NamedSource::new("", r#""a\qb" +"#.to_string());
+        let mut scanner = Scanner::new(&src);
+        let error = scanner.next().unwrap().unwrap_err().downcast().unwrap();
+        assert_matches!(error, ScannerError::InvalidEscape {
+             char: 'q',
+             src: _,
+             location,
+         } if location == SourceSpan::from(2..4));
+        assert_eq!(scanner.next().unwrap().unwrap().token_type, Plus);
+    }
+
+    #[test]
+    fn raise_error_on_invalid_unicode_escape() {
+        for input in [
+            r#""\u{}""#,
+            r#""\u{D800}""#,
+            r#""\u{1234567}""#,
+            r#""\u41""#,
+        ] {
+            let src = NamedSource::new("", input.to_string());
+            let scanner = Scanner::new(&src);
+            let result: Result<Vec<_>> = scanner.collect();
+            let error = result.unwrap_err().downcast().unwrap();
+            assert_matches!(error, ScannerError::InvalidUnicodeEscape { .. }, "{input}");
+        }
+    }
 }
@@ -1,3 +1,5 @@
+use std::borrow::Cow;
+
 use miette::SourceSpan;
 use strum::Display;
 
@@ -19,6 +21,10 @@ pub enum TokenType<'a> {
     LeftBrace,
     #[strum(serialize = "}}")]
     RightBrace,
+    #[strum(serialize = "[")]
+    LeftBracket,
+    #[strum(serialize = "]")]
+    RightBracket,
     #[strum(serialize = ",")]
     Comma,
     #[strum(serialize = ".")]
@@ -33,6 +39,18 @@ pub enum TokenType<'a> {
     Slash,
     #[strum(serialize = "*")]
     Star,
+    #[strum(serialize = "%")]
+    Percent,
+    #[strum(serialize = "&")]
+    Ampersand,
+    #[strum(serialize = "|")]
+    Pipe,
+    #[strum(serialize = "^")]
+    Caret,
+    #[strum(serialize = "?")]
+    Question,
+    #[strum(serialize = ":")]
+    Colon,
 
     // One or two character tokens.
     #[strum(serialize = "!")]
@@ -51,27 +69,62 @@ pub enum TokenType<'a> {
     Less,
     #[strum(serialize = "<=")]
     LessEqual,
+    #[strum(serialize = "=>")]
+    Arrow,
+    #[strum(serialize = "<<")]
+    LessLess,
+    #[strum(serialize = ">>")]
+    GreaterGreater,
+    #[strum(serialize = "**")]
+    StarStar,
+    #[strum(serialize = "+=")]
+    PlusEqual,
+    #[strum(serialize = "-=")]
+    MinusEqual,
+    #[strum(serialize = "*=")]
+    StarEqual,
+    #[strum(serialize = "/=")]
+    SlashEqual,
+    #[strum(serialize = "++")]
+    PlusPlus,
+    #[strum(serialize = "--")]
+    MinusMinus,
+    #[strum(serialize = "??")]
+    QuestionQuestion,
+    #[strum(serialize = "?.")]
+    QuestionDot,
+    // `//` starts a comment, so integer division is written `~/` as in Dart
+    #[strum(serialize = "~/")]
+    TildeSlash,
 
     // Literals
     Identifier(&'a str),
-    String(&'a str),
+    String(Cow<'a, str>),
+    /// The part of a string literal before a `${`, followed by the tokens of the expression
+    /// and a string token with the rest.
+    Interpolation(Cow<'a, str>),
     Number(f64),
 
     // Keywords.
     And,
+    Catch,
     Class,
     Else,
     False,
+    Finally,
     Fun,
     For,
     If,
+    Import,
     Nil,
     Or,
     Print,
     Return,
     Super,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
 }
@@ -80,13 +133,20 @@ pub enum TokenType<'a> {
 pub enum Precedence {
     None,
     Assignment,
+    Conditional,
+    Coalesce,
     Or,
     And,
     Equality,
     Comparision,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Shift,
     Term,
     Factor,
     Unary,
+    Power,
     Call,
 }
 
@@ -103,9 +163,13 @@ impl TokenType<'_> {
                 | True
                 | False
                 | String(_)
+                | Interpolation(_)
                 | Identifier(_)
                 | This
                 | Super
+                | Fun
+                | PlusPlus
+                | MinusMinus
         )
     }
 
@@ -113,12 +177,19 @@ impl TokenType<'_> {
         use TokenType::*;
         match self {
             Minus | Plus => Precedence::Term,
-            Star | Slash => Precedence::Factor,
+            Star | Slash | Percent | TildeSlash => Precedence::Factor,
+            StarStar => Precedence::Power,
+            Pipe => Precedence::BitOr,
+            Caret => Precedence::BitXor,
+            Ampersand => Precedence::BitAnd,
+            LessLess | GreaterGreater => Precedence::Shift,
             EqualEqual | BangEqual => Precedence::Equality,
             Greater | GreaterEqual | Less | LessEqual => Precedence::Comparision,
+            Question => Precedence::Conditional,
+            QuestionQuestion => Precedence::Coalesce,
             Or => Precedence::Or,
             And => Precedence::And,
-            LeftParen | Dot => Precedence::Call,
+            LeftParen | Dot | QuestionDot | LeftBracket | PlusPlus | MinusMinus => Precedence::Call,
             _ => Precedence::None,
         }
     }
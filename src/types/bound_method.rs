@@ -2,7 +2,7 @@ use std::fmt::{Debug, Display};
 
 use crate::{
     gc::{markable::Markable, Gc},
-    types::{obj_ref::ObjRef, value::Value, Hash, Hashable},
+    types::{obj::Obj, obj_ref::ObjRef, value::Value, Hash, Hashable},
 };
 
 pub struct BoundMethod {
@@ -55,15 +55,18 @@ impl Debug for BoundMethod {
 
 impl Display for BoundMethod {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            self.method()
-                .as_closure()
-                .function
-                .as_function()
-                .name()
-                .expect("can only be named functions")
-        )
+        match &*self.method {
+            Obj::Closure(closure) => write!(
+                f,
+                "{}",
+                closure
+                    .function
+                    .as_function()
+                    .name()
+                    .expect("can only be named functions")
+            ),
+            // the methods of strings and lists
+            method => write!(f, "{}", method),
+        }
     }
 }
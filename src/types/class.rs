@@ -9,6 +9,13 @@ use std::fmt::Display;
 pub struct Class {
     name: LoxString,
     methods: HashTable,
+    // `class` methods and fields of the class object, methods are called with the class as `this`
+    static_methods: HashTable,
+    static_fields: HashTable,
+    // incremented whenever methods change, invalidates inline caches
+    version: u32,
+    // instances are registered for finalization
+    has_finalizer: bool,
 }
 
 impl Class {
@@ -16,6 +23,10 @@ impl Class {
         Self {
             name,
             methods: HashTable::new(),
+            static_methods: HashTable::new(),
+            static_fields: HashTable::new(),
+            version: 0,
+            has_finalizer: false,
         }
     }
 
@@ -23,22 +34,66 @@ impl Class {
         &self.name
     }
 
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn has_finalizer(&self) -> bool {
+        self.has_finalizer
+    }
+
     pub fn add_method(&mut self, name: Value, method: Value) {
+        self.has_finalizer |= name.as_string().string == "finalize";
         self.methods.insert(name, method);
+        self.version = self.version.wrapping_add(1);
+    }
+
+    pub fn add_static_method(&mut self, name: Value, method: Value) {
+        self.static_methods.insert(name, method);
+    }
+
+    pub fn get_static_method(&self, name: Value) -> Option<Value> {
+        self.static_methods.get(name)
+    }
+
+    pub fn set_static_field(&mut self, name: Value, value: Value) {
+        self.static_fields.insert(name, value);
+    }
+
+    // bytes the next set_static_field allocates
+    pub fn static_field_growth(&self) -> usize {
+        self.static_fields.growth()
+    }
+
+    pub fn get_static_field(&self, name: Value) -> Option<Value> {
+        self.static_fields.get(name)
+    }
+
+    pub fn allocated_bytes(&self) -> usize {
+        self.methods.allocated_bytes()
+            + self.static_methods.allocated_bytes()
+            + self.static_fields.allocated_bytes()
     }
 
     pub fn get_method(&self, name: Value) -> Option<Value> {
         self.methods.get(name)
     }
 
+    /// Static fields are copied down with the methods, later changes of the superclass are not seen.
     pub fn copy_methods(&mut self, superclass: &Class) {
         self.methods.add_all(&superclass.methods);
+        self.static_methods.add_all(&superclass.static_methods);
+        self.static_fields.add_all(&superclass.static_fields);
+        self.has_finalizer |= superclass.has_finalizer;
+        self.version = self.version.wrapping_add(1);
     }
 }
 
 impl Markable for Class {
     fn mark(&mut self, gc: &mut crate::gc::Gc) {
         self.methods.mark(gc);
+        self.static_methods.mark(gc);
+        self.static_fields.mark(gc);
     }
 
     fn is_marked(&mut self) -> bool {
@@ -8,11 +8,17 @@ use crate::{
 pub struct Closure {
     pub function: ObjRef,
     pub upvalues: Vec<ObjRef>,
+    // globals are looked up in the module the closure was created in
+    pub module: ObjRef,
 }
 
 impl Closure {
-    pub fn new(function: ObjRef, upvalues: Vec<ObjRef>) -> Self {
-        Self { function, upvalues }
+    pub fn new(function: ObjRef, upvalues: Vec<ObjRef>, module: ObjRef) -> Self {
+        Self {
+            function,
+            upvalues,
+            module,
+        }
     }
 }
 
@@ -31,6 +37,7 @@ impl Hashable for Closure {
 impl Markable for Closure {
     fn mark(&mut self, gc: &mut Gc) {
         self.function.mark(gc);
+        self.module.mark(gc);
         self.upvalues.iter_mut().for_each(|u| u.mark(gc));
     }
 
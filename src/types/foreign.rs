@@ -0,0 +1,67 @@
+use std::{any::Any, fmt::Display};
+
+type DropHook = Box<dyn FnOnce(Box<dyn Any>)>;
+
+/// Rust value owned by the gc, e.g. an open file returned by a native.
+/// The drop hook runs once the object is found unreachable and freed, or when the VM is dropped.
+pub struct Foreign {
+    name: &'static str,
+    data: Option<Box<dyn Any>>,
+    drop_hook: Option<DropHook>,
+}
+
+impl Foreign {
+    pub fn new<T: Any>(name: &'static str, data: T) -> Self {
+        Self {
+            name,
+            data: Some(Box::new(data)),
+            drop_hook: None,
+        }
+    }
+
+    pub fn on_drop<T: Any>(mut self, hook: impl FnOnce(T) + 'static) -> Self {
+        self.drop_hook = Some(Box::new(move |data: Box<dyn Any>| {
+            hook(*data.downcast::<T>().expect("drop hook of a different type"))
+        }));
+        self
+    }
+
+    /// `None` if the data is of another type or was already released with `take`.
+    pub fn downcast_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.data.as_mut()?.downcast_mut()
+    }
+
+    /// Releases the data early, the drop hook does not run.
+    pub fn take<T: Any>(&mut self) -> Option<T> {
+        if !self.data.as_ref()?.is::<T>() {
+            return None;
+        }
+        self.data
+            .take()
+            .and_then(|data| data.downcast().ok())
+            .map(|data| *data)
+    }
+}
+
+impl Drop for Foreign {
+    fn drop(&mut self) {
+        if let (Some(hook), Some(data)) = (self.drop_hook.take(), self.data.take()) {
+            hook(data)
+        }
+    }
+}
+
+impl Display for Foreign {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<{}>", self.name)
+    }
+}
+
+impl std::fmt::Debug for Foreign {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Foreign")
+            .field("name", &self.name)
+            .field("released", &self.data.is_none())
+            .finish()
+    }
+}
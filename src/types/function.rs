@@ -44,11 +44,21 @@ impl Function {
     pub fn upvalues(&self) -> &[UpvalueIndex] {
         &self.upvalues
     }
+
+    // name and upvalues, the chunk is counted separately
+    pub fn owned_bytes(&self) -> usize {
+        self.name.as_ref().map_or(0, |name| name.string.capacity())
+            + self.upvalues.capacity() * size_of::<UpvalueIndex>()
+    }
 }
 
 impl Markable for Function {
     fn mark(&mut self, gc: &mut Gc) {
         self.chunk.constants.iter_mut().for_each(|c| gc.mark(c));
+        self.chunk
+            .caches
+            .iter_mut()
+            .for_each(|c| gc.mark(c.get_mut()));
     }
 
     fn is_marked(&mut self) -> bool {
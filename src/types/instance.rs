@@ -1,35 +1,57 @@
-use std::{fmt::Display, ops::Deref};
+use std::fmt::Display;
 
 use crate::{
-    datastructures::hash_table::HashTable,
+    datastructures::vector::LoxVector,
     gc::markable::Markable,
-    types::{class::Class, obj_ref::ObjRef, value::Value, Hashable},
+    types::{obj_ref::ObjRef, value::Value, Hashable},
 };
 
 #[derive(Debug)]
 pub struct Instance {
     class: ObjRef,
-    fields: HashTable,
+    shape: ObjRef,
+    fields: LoxVector<Value>,
 }
 
 impl Instance {
-    pub fn new(class: ObjRef) -> Self {
+    pub fn new(class: ObjRef, shape: ObjRef) -> Self {
         Self {
             class,
-            fields: HashTable::new(),
+            shape,
+            fields: LoxVector::new(),
         }
     }
 
-    pub fn class(&self) -> &Class {
-        self.class.deref().as_class()
+    pub fn class_obj(&self) -> ObjRef {
+        self.class
     }
 
-    pub fn get_field(&self, name: Value) -> Option<Value> {
-        self.fields.get(name)
+    pub fn shape(&self) -> ObjRef {
+        self.shape
     }
 
-    pub fn set_field(&mut self, name: Value, value: Value) {
-        self.fields.insert(name, value);
+    pub fn get_field_at(&self, slot: usize) -> Value {
+        self.fields[slot]
+    }
+
+    pub fn set_field_at(&mut self, slot: usize, value: Value) {
+        self.fields[slot] = value;
+    }
+
+    pub fn allocated_bytes(&self) -> usize {
+        self.fields.allocated_bytes()
+    }
+
+    // bytes the next add_field allocates
+    pub fn field_growth(&self) -> usize {
+        self.fields.growth()
+    }
+
+    // shape has to be the transition of the current shape for the added field
+    pub fn add_field(&mut self, shape: ObjRef, value: Value) {
+        self.fields.push(value);
+        self.shape = shape;
+        debug_assert_eq!(self.fields.len(), shape.as_shape().field_count());
     }
 }
 
@@ -42,7 +64,8 @@ impl Hashable for Instance {
 impl Markable for Instance {
     fn mark(&mut self, gc: &mut crate::gc::Gc) {
         self.class.mark(gc);
-        self.fields.mark(gc);
+        self.shape.mark(gc);
+        self.fields.iter_mut().for_each(|f| f.mark(gc));
     }
 
     fn is_marked(&mut self) -> bool {
@@ -1,10 +1,13 @@
 pub mod bound_method;
 pub mod class;
 pub mod closure;
+pub mod foreign;
 pub mod function;
 pub mod instance;
+pub mod module;
 pub mod obj;
 pub mod obj_ref;
+pub mod shape;
 pub mod string;
 pub mod upvalue;
 pub mod value;
@@ -0,0 +1,54 @@
+use crate::{
+    datastructures::hash_table::HashTable,
+    gc::markable::Markable,
+    types::{string::LoxString, value::Value},
+};
+use std::fmt::Display;
+
+/// The global variables of a script, every closure belongs to the module it was created in.
+#[derive(Debug)]
+pub struct Module {
+    name: LoxString,
+    // key in the module cache, nil for modules which were not imported
+    path: Value,
+    pub globals: HashTable,
+}
+
+impl Module {
+    pub fn new(name: LoxString, path: Value) -> Self {
+        Self {
+            name,
+            path,
+            globals: HashTable::new(),
+        }
+    }
+
+    pub fn name(&self) -> &LoxString {
+        &self.name
+    }
+
+    pub fn path(&self) -> Value {
+        self.path
+    }
+
+    pub fn allocated_bytes(&self) -> usize {
+        self.globals.allocated_bytes()
+    }
+}
+
+impl Markable for Module {
+    fn mark(&mut self, gc: &mut crate::gc::Gc) {
+        self.path.mark(gc);
+        self.globals.mark(gc);
+    }
+
+    fn is_marked(&mut self) -> bool {
+        unreachable!()
+    }
+}
+
+impl Display for Module {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<module {}>", self.name)
+    }
+}
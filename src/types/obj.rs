@@ -2,29 +2,47 @@ use std::fmt::{Debug, Display};
 
 use super::bound_method::BoundMethod;
 use super::closure::Closure;
+use super::foreign::Foreign;
 use super::function::Function;
 use super::instance::Instance;
+use super::module::Module;
 use super::obj_ref::ObjRef;
+use super::shape::Shape;
 use super::string::LoxString;
 use super::value::Value;
 use super::Hashable;
+use crate::datastructures::hash_table::HashTable;
+use crate::datastructures::vector::LoxVector;
 use crate::types::class::Class;
 use crate::types::Hash;
 use crate::vm::VM;
 pub struct ObjStruct {
     pub obj: Obj,
     pub marked: bool,
+    // survived a collection and got promoted to the old generation
+    pub old: bool,
+    // old object in the remembered set, as it may reference young objects
+    pub remembered: bool,
+    #[cfg(feature = "verify_gc")]
+    pub verify: crate::gc::verify::VerifyInfo,
 }
 
 impl ObjStruct {
     pub fn new(obj: Obj) -> Self {
-        Self { obj, marked: false }
+        Self {
+            obj,
+            marked: false,
+            old: false,
+            remembered: false,
+            #[cfg(feature = "verify_gc")]
+            verify: Default::default(),
+        }
     }
 }
 pub enum Obj {
     String(LoxString),
     Function(Function),
-    Native(fn(u8, *mut Value, &mut VM) -> Value),
+    Native(fn(u8, *mut Value, &mut VM) -> miette::Result<Value>),
     Closure(Closure),
     Upvalue {
         location: *mut Value,
@@ -34,9 +52,81 @@ pub enum Obj {
     Class(Class),
     Instance(Instance),
     BoundMethod(BoundMethod),
+    Shape(Shape),
+    // target is not traced and cleared to nil once it dies
+    WeakRef(Value),
+    // keys are not traced, entries are removed once their key dies
+    WeakMap(HashTable),
+    Foreign(Foreign),
+    Module(Module),
+    // returned by string methods like split, can be indexed
+    List(LoxVector<Value>),
 }
 
 impl Obj {
+    pub const KINDS: [&'static str; 14] = [
+        "string",
+        "function",
+        "native",
+        "closure",
+        "upvalue",
+        "class",
+        "instance",
+        "bound_method",
+        "shape",
+        "weak_ref",
+        "weak_map",
+        "foreign",
+        "module",
+        "list",
+    ];
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Obj::String(_) => "string",
+            Obj::Function(_) => "function",
+            Obj::Native(_) => "native",
+            Obj::Closure(_) => "closure",
+            Obj::Upvalue { .. } => "upvalue",
+            Obj::Class(_) => "class",
+            Obj::Instance(_) => "instance",
+            Obj::BoundMethod(_) => "bound_method",
+            Obj::Shape(_) => "shape",
+            Obj::WeakRef(_) => "weak_ref",
+            Obj::WeakMap(_) => "weak_map",
+            Obj::Foreign(_) => "foreign",
+            Obj::Module(_) => "module",
+            Obj::List(_) => "list",
+        }
+    }
+
+    // bytes owned besides the gc node and the growable buffers, fixed after allocation
+    pub fn owned_bytes(&self) -> usize {
+        match self {
+            Obj::String(string) => string.string.capacity(),
+            Obj::Function(function) => function.owned_bytes(),
+            Obj::Closure(closure) => closure.upvalues.capacity() * size_of::<ObjRef>(),
+            Obj::Class(class) => class.name().string.capacity(),
+            Obj::Module(module) => module.name().string.capacity(),
+            _ => 0,
+        }
+    }
+
+    // all bytes owned besides the gc node, including the growable buffers
+    pub fn allocated_bytes(&self) -> usize {
+        self.owned_bytes()
+            + match self {
+                Obj::Function(function) => function.chunk().allocated_bytes(),
+                Obj::Class(class) => class.allocated_bytes(),
+                Obj::Instance(instance) => instance.allocated_bytes(),
+                Obj::Shape(shape) => shape.allocated_bytes(),
+                Obj::WeakMap(table) => table.allocated_bytes(),
+                Obj::Module(module) => module.allocated_bytes(),
+                Obj::List(items) => items.allocated_bytes(),
+                _ => 0,
+            }
+    }
+
     pub fn as_function(&self) -> &Function {
         if let Obj::Function(function) = self {
             function
@@ -69,6 +159,70 @@ impl Obj {
         }
     }
 
+    pub fn as_instance(&self) -> &Instance {
+        if let Obj::Instance(instance) = self {
+            instance
+        } else {
+            panic!("Value is no Instance")
+        }
+    }
+
+    pub fn as_instance_mut(&mut self) -> &mut Instance {
+        if let Obj::Instance(instance) = self {
+            instance
+        } else {
+            panic!("Value is no Instance")
+        }
+    }
+
+    pub fn as_list(&self) -> &LoxVector<Value> {
+        if let Obj::List(items) = self {
+            items
+        } else {
+            panic!("Value is no List")
+        }
+    }
+
+    pub fn as_list_mut(&mut self) -> &mut LoxVector<Value> {
+        if let Obj::List(items) = self {
+            items
+        } else {
+            panic!("Value is no List")
+        }
+    }
+
+    pub fn as_module(&self) -> &Module {
+        if let Obj::Module(module) = self {
+            module
+        } else {
+            panic!("Value is no Module")
+        }
+    }
+
+    pub fn as_module_mut(&mut self) -> &mut Module {
+        if let Obj::Module(module) = self {
+            module
+        } else {
+            panic!("Value is no Module")
+        }
+    }
+
+    pub fn as_shape(&self) -> &Shape {
+        if let Obj::Shape(shape) = self {
+            shape
+        } else {
+            panic!("Value is no Shape")
+        }
+    }
+
+    pub fn as_shape_mut(&mut self) -> &mut Shape {
+        if let Obj::Shape(shape) = self {
+            shape
+        } else {
+            panic!("Value is no Shape")
+        }
+    }
+
     pub fn as_closure(&self) -> &Closure {
         if let Obj::Closure(closure) = self {
             closure
@@ -91,6 +245,12 @@ impl Hashable for Obj {
             Obj::Class(class) => class.name().hash(),
             Obj::Instance(instance) => instance.hash(),
             Obj::BoundMethod(bound_method) => bound_method.hash(),
+            Obj::Shape(_) => Hash(13),
+            Obj::WeakRef(_) => Hash(17),
+            Obj::WeakMap(_) => Hash(19),
+            Obj::Foreign(_) => Hash(23),
+            Obj::Module(module) => module.name().hash(),
+            Obj::List(_) => Hash(29),
         }
     }
 }
@@ -106,6 +266,21 @@ impl Display for Obj {
             Obj::Class(class) => write!(f, "{}", class),
             Obj::Instance(instance) => write!(f, "{}", instance),
             Obj::BoundMethod(bound_method) => write!(f, "{}", bound_method),
+            Obj::Shape(shape) => write!(f, "{}", shape),
+            Obj::WeakRef(_) => write!(f, "<weak ref>"),
+            Obj::WeakMap(_) => write!(f, "<weak map>"),
+            Obj::Foreign(foreign) => write!(f, "{}", foreign),
+            Obj::Module(module) => write!(f, "{}", module),
+            Obj::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
         }
     }
 }
@@ -132,6 +307,12 @@ impl Debug for Obj {
             Self::Class(class) => Debug::fmt(class, f),
             Self::Instance(instance) => Debug::fmt(instance, f),
             Self::BoundMethod(bound_method) => Debug::fmt(bound_method, f),
+            Self::Shape(shape) => Debug::fmt(shape, f),
+            Self::WeakRef(target) => f.debug_tuple("WeakRef").field(target).finish(),
+            Self::WeakMap(table) => f.debug_tuple("WeakMap").field(table).finish(),
+            Self::Foreign(foreign) => Debug::fmt(foreign, f),
+            Self::Module(module) => Debug::fmt(module, f),
+            Self::List(items) => f.debug_tuple("List").field(items).finish(),
         }
     }
 }
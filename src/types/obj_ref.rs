@@ -15,6 +15,11 @@ impl ObjRef {
     pub fn new(ptr: NonNull<Node>) -> Self {
         ObjRef(ptr)
     }
+
+    pub fn is_old(&self) -> bool {
+        // SAFETY ptr is guaranteed to be managed by GC
+        unsafe { self.0.as_ref().obj_struct.old }
+    }
 }
 
 impl Deref for ObjRef {
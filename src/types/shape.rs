@@ -0,0 +1,101 @@
+use std::fmt::Display;
+
+use crate::{
+    datastructures::hash_table::HashTable,
+    gc::{markable::Markable, Gc},
+    types::{obj_ref::ObjRef, value::Value},
+};
+
+/// Hidden class of an instance, maps field names to slots in the field vector of the instance.
+/// Instances that got the same fields assigned in the same order share a shape.
+#[derive(Debug)]
+pub struct Shape {
+    slots: HashTable,
+    field_count: usize,
+    // shapes reached by adding one more field
+    transitions: HashTable,
+}
+
+impl Shape {
+    pub fn new() -> Self {
+        Self {
+            slots: HashTable::new(),
+            field_count: 0,
+            transitions: HashTable::new(),
+        }
+    }
+
+    pub fn slot(&self, name: Value) -> Option<usize> {
+        if let Some(Value::Number(slot)) = self.slots.get(name) {
+            Some(slot as usize)
+        } else {
+            None
+        }
+    }
+
+    pub fn field_count(&self) -> usize {
+        self.field_count
+    }
+
+    pub fn transition(&self, name: Value) -> Option<ObjRef> {
+        self.transitions.get(name).map(|shape| *shape.as_obj())
+    }
+
+    pub fn add_transition(&mut self, name: Value, shape: ObjRef) {
+        self.transitions.insert(name, Value::Obj(shape));
+    }
+
+    pub fn allocated_bytes(&self) -> usize {
+        self.slots.allocated_bytes() + self.transitions.allocated_bytes()
+    }
+
+    // bytes the next add_transition allocates
+    pub fn transition_growth(&self) -> usize {
+        self.transitions.growth()
+    }
+
+    pub fn with_field(&self, name: Value) -> Shape {
+        let mut slots = HashTable::new();
+        slots.add_all(&self.slots);
+        slots.insert(name, Value::Number(self.field_count as f64));
+        Self {
+            slots,
+            field_count: self.field_count + 1,
+            transitions: HashTable::new(),
+        }
+    }
+}
+
+impl Markable for Shape {
+    fn mark(&mut self, gc: &mut Gc) {
+        self.slots.mark(gc);
+        self.transitions.mark(gc);
+    }
+
+    fn is_marked(&mut self) -> bool {
+        unreachable!()
+    }
+}
+
+impl Display for Shape {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "shape with {} fields", self.field_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn with_field_appends_slot() {
+        let mut gc = Gc::new();
+        let x = Value::Obj(gc.alloc("x"));
+        let y = Value::Obj(gc.alloc("y"));
+        let shape = Shape::new().with_field(x).with_field(y);
+        assert_eq!(shape.field_count(), 2);
+        assert_eq!(shape.slot(x), Some(0));
+        assert_eq!(shape.slot(y), Some(1));
+        assert_eq!(shape.slot(Value::Nil), None);
+    }
+}
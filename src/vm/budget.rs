@@ -0,0 +1,47 @@
+use std::time::{Duration, Instant};
+
+use crate::error::InterpreterError;
+
+use super::VM;
+
+/// Instructions executed between two reads of the clock.
+const DEADLINE_CHECK_INTERVAL: u64 = 10_000;
+
+impl VM {
+    /// Allows `budget` more instructions to run, `None` removes the limit.
+    /// Set a new budget before calling [`VM::resume`] after the previous one was exhausted.
+    pub fn set_instruction_budget(&mut self, budget: Option<u64>) {
+        self.instruction_limit = budget.map(|budget| self.instructions_executed + budget);
+    }
+
+    /// Stops execution once `deadline` has passed, `None` removes the deadline.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+        self.next_deadline_check = self.instructions_executed;
+    }
+
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.set_deadline(timeout.map(|timeout| Instant::now() + timeout));
+    }
+
+    /// Called after backward jumps and calls, straight line code between them is bounded by the size of the chunk.
+    /// Runs pending finalizers, an exhausted budget stops without unwinding so execution can be resumed.
+    pub(super) fn safepoint(&mut self) -> miette::Result<()> {
+        self.run_finalizers()?;
+        if self
+            .instruction_limit
+            .is_some_and(|limit| self.instructions_executed >= limit)
+        {
+            return Err(InterpreterError::InstructionBudgetExhausted.into());
+        }
+        if let Some(deadline) = self.deadline {
+            if self.instructions_executed >= self.next_deadline_check {
+                self.next_deadline_check = self.instructions_executed + DEADLINE_CHECK_INTERVAL;
+                if Instant::now() >= deadline {
+                    return Err(InterpreterError::DeadlineExceeded.into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+use super::VM;
+
+pub const DEFAULT_MAX_FRAMES: usize = 64;
+pub const DEFAULT_MAX_STACK: usize = 256 * DEFAULT_MAX_FRAMES;
+pub const DEFAULT_GC_SLICE_BUDGET: usize = if cfg!(feature = "stress_incremental_gc") {
+    1
+} else {
+    100
+};
+
+pub struct VMBuilder {
+    pub(super) max_frames: usize,
+    pub(super) max_stack: usize,
+    pub(super) gc_slice_budget: usize,
+    pub(super) max_heap_size: Option<usize>,
+    pub(super) max_instructions: Option<u64>,
+    pub(super) timeout: Option<Duration>,
+    pub(super) file_access: bool,
+}
+
+impl VMBuilder {
+    pub fn new() -> Self {
+        Self {
+            max_frames: DEFAULT_MAX_FRAMES,
+            max_stack: DEFAULT_MAX_STACK,
+            gc_slice_budget: DEFAULT_GC_SLICE_BUDGET,
+            max_heap_size: None,
+            max_instructions: None,
+            timeout: None,
+            file_access: false,
+        }
+    }
+
+    /// Maximum depth of the call stack, at least 1 for the script itself.
+    pub fn max_frames(mut self, max_frames: usize) -> Self {
+        self.max_frames = max_frames.max(1);
+        self
+    }
+
+    /// Maximum number of values on the value stack, checked when entering a function.
+    /// The stack grows on demand but not past this limit, below its initial 256 values only calls are limited.
+    pub fn max_stack(mut self, max_stack: usize) -> Self {
+        self.max_stack = max_stack.max(1);
+        self
+    }
+
+    /// Number of objects marked or swept per allocation while a collection of the whole heap is running, at least 1.
+    /// Smaller budgets mean shorter pauses but longer collections.
+    pub fn gc_slice_budget(mut self, gc_slice_budget: usize) -> Self {
+        self.gc_slice_budget = gc_slice_budget.max(1);
+        self
+    }
+
+    /// Bytes the heap may grow to, allocating beyond raises an "out of memory" runtime error.
+    pub fn max_heap_size(mut self, max_heap_size: Option<usize>) -> Self {
+        self.max_heap_size = max_heap_size;
+        self
+    }
+
+    /// Instructions each call to `interpret` may execute before stopping with a resumable error.
+    pub fn max_instructions(mut self, max_instructions: Option<u64>) -> Self {
+        self.max_instructions = max_instructions;
+        self
+    }
+
+    /// Wall-clock time each call to `interpret` may run before stopping with a resumable error.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Defines the natives `open`, `read_line`, `write` and `close`, giving scripts access to the filesystem.
+    pub fn file_access(mut self, file_access: bool) -> Self {
+        self.file_access = file_access;
+        self
+    }
+
+    pub fn build(self) -> VM {
+        VM::from_builder(self)
+    }
+}
+
+impl Default for VMBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -3,7 +3,7 @@ use std::ops::Deref;
 use miette::SourceSpan;
 
 use crate::{
-    chunk::Chunk,
+    chunk::{Chunk, ExceptionHandler},
     op::Op,
     types::{function::Function, obj::Obj, obj_ref::ObjRef, value::Value},
 };
@@ -12,6 +12,8 @@ pub(super) struct CallFrame {
     pub(super) closure: ObjRef,
     pub(super) ip: *const Op,
     pub(super) slots: *mut Value,
+    // frame replaced the frame of a function returning the result of this call
+    pub(super) tail_call: bool,
 }
 
 impl CallFrame {
@@ -32,6 +34,10 @@ impl CallFrame {
         }
     }
 
+    pub(super) fn module(&self) -> ObjRef {
+        self.closure.as_closure().module
+    }
+
     pub(super) fn chunk(&self) -> &Chunk {
         self.function().chunk()
     }
@@ -39,8 +45,15 @@ impl CallFrame {
         unsafe { self.ip.offset_from(&(*self.function()).chunk().code[0]) as usize }
     }
 
+    // location of the instruction currently executed, ip already points to the next one
     pub(super) fn current_location(&self) -> SourceSpan {
-        self.chunk().locations[self.current_index()]
+        self.chunk().locations[self.current_index() - 1]
+    }
+
+    // handler of a try statement around the instruction currently executed
+    pub(super) fn exception_handler(&self) -> Option<ExceptionHandler> {
+        let executed = self.current_index().checked_sub(1)?;
+        self.chunk().find_handler(executed)
     }
 
     pub(super) fn disassemble_at_current_index(&mut self) -> String {
@@ -0,0 +1,137 @@
+use miette::{NamedSource, Report, SourceCode};
+
+use crate::{
+    error::{InterpreterError, Interrupted, Thrown},
+    types::{
+        instance::Instance, module::Module, obj::Obj, obj_ref::ObjRef, string::LoxString,
+        value::Value,
+    },
+};
+
+use super::VM;
+
+/// Defines the class of the errors raised by the VM, scripts can throw and extend it as well.
+const PRELUDE: &str = "
+class Error {
+  init(message) {
+    this.message = message;
+  }
+}
+";
+
+impl VM {
+    /// The prelude runs in a module of its own, `Error` is a builtin so every module can see it.
+    pub(super) fn define_error_class(&mut self) {
+        let prelude = self.gc.alloc(Obj::Module(Module::new(
+            LoxString::from_str("prelude"),
+            Value::Nil,
+        )));
+        self.interpret_in(NamedSource::new("prelude", PRELUDE.to_string()), prelude)
+            .expect("prelude is valid");
+        let name = Value::Obj(self.gc.alloc("Error"));
+        let class = prelude
+            .as_module()
+            .globals
+            .get(name)
+            .expect("defined by the prelude");
+        self.builtins.insert(name, class);
+        self.error_class = class;
+    }
+
+    /// The error raised by `throw` for the value on top of the stack.
+    pub(super) fn thrown(&mut self) -> Report {
+        let value = self.peek(0);
+        let mut description = value.to_string();
+        if let Some(Value::Obj(message)) = self.field(value, "message") {
+            if let Obj::String(message) = &*message {
+                description = format!("{description}: {}", message.string);
+            }
+        }
+        Thrown {
+            description,
+            location: self.current_frame().current_location(),
+        }
+        .into()
+    }
+
+    /// Continues at the innermost handler of the frames above base_frame, the error is returned
+    /// if there is none or it can not be caught. Errors raised by the VM are caught as instances
+    /// of `Error` with the message and where they happened.
+    pub(super) fn catch(&mut self, error: Report, base_frame: usize) -> miette::Result<()> {
+        if error.is::<InterpreterError>() || error.is::<Interrupted>() {
+            return Err(error);
+        }
+        let Some((frame_index, handler)) = (base_frame..self.frame_count).rev().find_map(|i| {
+            let frame = unsafe { &*self.frames.add(i) };
+            frame.exception_handler().map(|handler| (i, handler))
+        }) else {
+            return Err(error);
+        };
+        let exception = if error.is::<Thrown>() {
+            self.pop()
+        } else if let Ok(exception) = self.error_object(&error) {
+            exception
+        } else {
+            return Err(error);
+        };
+
+        self.abandon_imports(frame_index + 1);
+        self.frame_count = frame_index + 1;
+        let frame = self.current_frame();
+        let stack_top = unsafe { frame.slots.add(handler.stack_depth) };
+        frame.ip = unsafe { frame.chunk().code.ptr().add(handler.target) };
+        self.close_upvalues(stack_top);
+        self.stack_top = stack_top;
+        self.push(exception)?;
+        Ok(())
+    }
+
+    fn error_object(&mut self, error: &Report) -> miette::Result<Value> {
+        let class = *self.error_class.as_obj();
+        let instance = self.alloc(Obj::Instance(Instance::new(class, self.empty_shape)))?;
+        self.push(Value::Obj(instance))?;
+        let message = self.alloc(error.to_string())?;
+        self.add_field(instance, "message", Value::Obj(message))?;
+        if let Some(label) = error.labels().and_then(|mut labels| labels.next()) {
+            let source = self.current_frame().chunk().source.clone();
+            let position = source
+                .read_span(label.inner(), 0, 0)
+                .map(|contents| (contents.line() + 1, contents.column() + 1));
+            if let Ok((line, column)) = position {
+                self.add_field(instance, "line", Value::Number(line as f64))?;
+                self.add_field(instance, "column", Value::Number(column as f64))?;
+            }
+        }
+        Ok(self.pop())
+    }
+
+    // for instances scripts can not see yet, the value has to be reachable until it is stored
+    fn add_field(&mut self, mut instance: ObjRef, name: &str, value: Value) -> miette::Result<()> {
+        self.push(value)?;
+        let name = Value::Obj(self.alloc(name)?);
+        self.push(name)?;
+        let next = self.shape_transition(instance.as_instance().shape(), name)?;
+        self.reserve(instance.as_instance().field_growth())?;
+        let before = instance.as_instance().allocated_bytes();
+        instance.as_instance_mut().add_field(next, value);
+        self.gc
+            .charge(before, instance.as_instance().allocated_bytes());
+        self.gc.write_barrier(instance, Value::Obj(next));
+        self.gc.write_barrier(instance, value);
+        self.pop();
+        self.pop();
+        Ok(())
+    }
+
+    fn field(&mut self, value: Value, name: &str) -> Option<Value> {
+        let Value::Obj(obj) = value else {
+            return None;
+        };
+        let Obj::Instance(instance) = &*obj else {
+            return None;
+        };
+        let name = Value::Obj(self.gc.alloc(name));
+        let slot = instance.shape().as_shape().slot(name)?;
+        Some(instance.get_field_at(slot))
+    }
+}
@@ -1,65 +1,278 @@
-use tracing::debug;
+use miette::LabeledSpan;
+use tracing::{debug, warn};
 
-use crate::{gc::alloc::Alloc, types::obj_ref::ObjRef};
+use crate::{
+    error::{InterpreterError, Interrupted},
+    gc::{
+        alloc::Alloc,
+        snapshot::{HeapSnapshot, Root},
+        stats::GcStats,
+        Phase,
+    },
+    types::{obj_ref::ObjRef, value::Value},
+};
 
 use super::{UpvalueLocation, VM};
 // Mostly separated to get better scoping for tracing targets
 impl VM {
+    // finishes a running incremental collection and then collects the whole heap at once
     pub fn collect_garbage(&mut self) {
+        while self.gc.phase() != Phase::Idle {
+            self.collect_garbage_slice(usize::MAX);
+        }
+        self.begin_collection();
+        while self.gc.phase() != Phase::Idle {
+            self.collect_garbage_slice(usize::MAX);
+        }
+    }
+
+    /// Live objects and the bytes they use per kind, collects the whole heap first.
+    pub fn gc_stats(&mut self) -> GcStats {
+        self.collect_garbage();
+        self.gc.stats()
+    }
+
+    fn begin_collection(&mut self) {
         debug!("gc begin");
+        self.gc_bytes_before = self.gc.bytes_allocated();
+        self.gc.begin_mark();
+        self.mark_roots();
+    }
+
+    // performs at most budget units of marking or sweeping work
+    fn collect_garbage_slice(&mut self, budget: usize) {
+        match self.gc.phase() {
+            Phase::Idle => (),
+            Phase::Mark => {
+                if self.gc.trace_slice(budget) {
+                    // roots have no write barrier, so they are scanned again before marking ends
+                    self.mark_roots();
+                    self.gc.trace_references();
+                    self.gc.begin_sweep();
+                }
+            }
+            Phase::Sweep => {
+                if self.gc.sweep_slice(budget) {
+                    self.gc.reset_next_gc();
+                    #[cfg(feature = "verify_gc")]
+                    self.verify_heap();
+                    debug!("gc end");
+                    debug!(
+                        "collected {} bytes (from {} to {}) next at {}",
+                        self.gc_bytes_before
+                            .saturating_sub(self.gc.bytes_allocated()),
+                        self.gc_bytes_before,
+                        self.gc.bytes_allocated(),
+                        self.gc.next_gc()
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn collect_young_garbage(&mut self) {
+        debug!("young gc begin");
         let before = self.gc.bytes_allocated();
 
+        self.gc.begin_young_collection();
         self.mark_roots();
+        self.gc.mark_remembered();
         self.gc.trace_references();
+        self.gc.queue_finalizers();
         self.gc.clean_stringpool();
-        self.gc.sweep();
-        self.gc.reset_next_gc();
+        self.gc.clear_weak_references();
+        self.gc.sweep_young();
+        #[cfg(feature = "verify_gc")]
+        self.verify_heap();
 
-        debug!("gc end");
+        debug!("young gc end");
         debug!(
-            "collected {} bytes (from {} to {}) next at {}",
+            "collected {} bytes (from {} to {})",
             before - self.gc.bytes_allocated(),
             before,
             self.gc.bytes_allocated(),
-            self.gc.next_gc()
         );
     }
 
-    pub(super) fn alloc(&mut self, object: impl Alloc) -> ObjRef {
+    /// Runs the `finalize` method of unreachable instances on top of the current frame.
+    /// Runtime errors are logged and otherwise ignored, finalizers queued while running are run as well.
+    /// An interrupt or an exhausted budget abandons the finalizer and stops the code it ran on top of.
+    pub(super) fn run_finalizers(&mut self) -> miette::Result<()> {
+        if self.running_finalizers || !self.gc.has_pending_finalizers() {
+            return Ok(());
+        }
+        self.running_finalizers = true;
+        while let Some(instance) = self.gc.next_finalizer() {
+            debug!("{:p} finalize {}", instance.0.as_ptr(), *instance);
+            let base_frame = self.frame_count;
+            let stack_len = self.stack_len();
+            let result = self
+                .push(Value::Obj(instance))
+                .and_then(|()| self.run_finalizer(instance, base_frame));
+            // the finalizer may have grown and moved the stack
+            let stack_top = unsafe { self.stack.add(stack_len) };
+            if let Err(mut err) = result {
+                self.close_upvalues(stack_top);
+                self.frame_count = base_frame;
+                self.stack_top = stack_top;
+                if err.is::<InterpreterError>() || err.is::<Interrupted>() {
+                    if let Some(interrupted) = err.downcast_mut::<Interrupted>() {
+                        if self.frame_count > 0 {
+                            interrupted.location = self.current_frame().current_location();
+                        }
+                    }
+                    self.running_finalizers = false;
+                    return Err(err);
+                }
+                warn!("finalizer of {} failed: {}", *instance, err);
+            }
+            self.stack_top = stack_top;
+        }
+        self.running_finalizers = false;
+        Ok(())
+    }
+
+    fn run_finalizer(&mut self, instance: ObjRef, base_frame: usize) -> miette::Result<()> {
+        let name = Value::Obj(self.gc.alloc("finalize"));
+        let method = instance
+            .as_instance()
+            .class_obj()
+            .as_class()
+            .get_method(name)
+            .expect("only instances of classes with a finalizer are registered");
+        let closure = method.as_closure();
+        if closure.function.as_function().arity() != 0 {
+            miette::bail!("finalize must not take arguments");
+        }
+        self.call(0, *method.as_obj(), closure)?;
+        self.interpret_inner(base_frame)
+    }
+
+    // may collect, so everything referenced by object has to be reachable from the roots
+    pub(super) fn alloc(&mut self, object: impl Alloc) -> miette::Result<ObjRef> {
         #[cfg(feature = "stress_gc")]
         self.collect_garbage();
 
-        if self.gc.should_gc() {
-            self.collect_garbage();
+        #[cfg(feature = "stress_young_gc")]
+        if self.gc.phase() == Phase::Idle {
+            self.collect_young_garbage();
+        }
+
+        #[cfg(feature = "stress_incremental_gc")]
+        if self.gc.phase() == Phase::Idle {
+            self.begin_collection();
+        }
+
+        if self.gc.phase() != Phase::Idle {
+            self.collect_garbage_slice(self.gc_slice_budget);
+        } else if self.gc.should_gc() {
+            self.begin_collection();
+        } else if self.gc.should_collect_young() {
+            self.collect_young_garbage();
         }
-        self.gc.alloc(object)
+
+        self.reserve(object.size())?;
+        let obj = self.gc.alloc(object);
+        #[cfg(feature = "verify_gc")]
+        if self.frame_count > 0 {
+            let span = self.current_frame().current_location();
+            let source = self.current_frame().chunk().source.clone();
+            self.gc.set_alloc_site(obj, span, source);
+        }
+        Ok(obj)
     }
 
-    fn mark_roots(&mut self) {
-        let mut stack_ptr = self.stack;
-        while stack_ptr < self.stack_top {
-            (unsafe {
-                self.gc.mark(&mut *stack_ptr);
-            });
-            stack_ptr = unsafe { stack_ptr.add(1) };
+    /// Raises "out of memory" if the heap can not grow by `bytes` even after a collection.
+    /// Checked before objects are allocated, buffers of objects grow or large strings are built.
+    // may collect, so everything the bytes are for has to be reachable from the roots
+    pub(super) fn reserve(&mut self, bytes: usize) -> miette::Result<()> {
+        if !self.gc.exceeds_max_heap_size(bytes) {
+            return Ok(());
+        }
+        self.collect_garbage();
+        if self.gc.exceeds_max_heap_size(bytes) {
+            let labels = if self.frame_count > 0 {
+                vec![LabeledSpan::at(
+                    self.current_frame().current_location(),
+                    "here",
+                )]
+            } else {
+                vec![]
+            };
+            miette::bail!(labels = labels, "out of memory");
         }
+        Ok(())
+    }
 
-        for i in 0..self.frame_count {
-            unsafe { self.gc.mark(&mut (*self.frames.add(i)).closure) };
+    fn mark_roots(&mut self) {
+        for root in Root::ALL {
+            self.mark_root(root);
         }
+    }
 
-        let mut upvalue = self.open_upvalues;
-        while let Some(UpvalueLocation {
-            location: _,
-            mut current,
-            next,
-        }) = Self::upvalue_location(upvalue)
-        {
-            self.gc.mark(&mut current);
-            upvalue = next;
+    fn mark_root(&mut self, root: Root) {
+        match root {
+            Root::Stack => {
+                let mut stack_ptr = self.stack;
+                while stack_ptr < self.stack_top {
+                    (unsafe {
+                        self.gc.mark(&mut *stack_ptr);
+                    });
+                    stack_ptr = unsafe { stack_ptr.add(1) };
+                }
+            }
+            Root::Frame => {
+                for i in 0..self.frame_count {
+                    unsafe { self.gc.mark(&mut (*self.frames.add(i)).closure) };
+                }
+            }
+            Root::Upvalue => {
+                let mut upvalue = self.open_upvalues;
+                while let Some(UpvalueLocation {
+                    location: _,
+                    mut current,
+                    next,
+                }) = Self::upvalue_location(upvalue)
+                {
+                    self.gc.mark(&mut current);
+                    upvalue = next;
+                }
+            }
+            Root::Global => {
+                self.builtins.mark(&mut self.gc);
+                self.string_methods.mark(&mut self.gc);
+                self.list_methods.mark(&mut self.gc);
+                self.gc.mark(&mut self.main_module);
+            }
+            Root::Module => self.modules.mark(&mut self.gc),
+            Root::InitString => self.gc.mark(&mut self.init_string),
+            Root::EmptyShape => self.gc.mark(&mut self.empty_shape),
+            Root::ErrorClass => self.gc.mark(&mut self.error_class),
+            Root::PendingFinalizer => self.gc.mark_pending_finalizers(),
         }
+    }
+
+    // the objects each root references, without marking them
+    fn record_roots(&mut self) -> Vec<(Root, Vec<ObjRef>)> {
+        Root::ALL
+            .into_iter()
+            .map(|root| {
+                self.gc.begin_recording();
+                self.mark_root(root);
+                (root, self.gc.end_recording())
+            })
+            .collect()
+    }
+
+    /// Every object reachable from the roots with its references, nothing is collected.
+    pub fn heap_snapshot(&mut self) -> HeapSnapshot {
+        let roots = self.record_roots();
+        self.gc.snapshot(roots)
+    }
 
-        self.globals.mark(&mut self.gc);
-        self.gc.mark(&mut self.init_string);
+    #[cfg(feature = "verify_gc")]
+    fn verify_heap(&mut self) {
+        let roots = self.record_roots();
+        self.gc.verify(roots);
     }
 }
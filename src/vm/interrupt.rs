@@ -0,0 +1,49 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use crate::error::Interrupted;
+
+use super::VM;
+
+/// Cancels the script running on a [`VM`], can be sent to other threads.
+#[derive(Clone, Debug)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    pub(super) fn new() -> Self {
+        Self(Arc::new(AtomicBool::new(false)))
+    }
+
+    /// Aborts the running script with an "interrupted" runtime error at its next loop iteration or call.
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    fn clear(&self) -> bool {
+        self.0.swap(false, Ordering::Relaxed)
+    }
+}
+
+impl VM {
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    /// Forgets interrupts that arrived while no script was running.
+    pub(super) fn clear_interrupt(&self) {
+        self.interrupt.clear();
+    }
+
+    /// Called before backward jumps and calls, so the error points at them.
+    pub(super) fn check_interrupt(&mut self) -> miette::Result<()> {
+        if self.interrupt.clear() {
+            return Err(Interrupted {
+                location: self.current_frame().current_location(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
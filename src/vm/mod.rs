@@ -1,44 +1,79 @@
+mod budget;
+mod builder;
 mod callframe;
+mod exception;
 mod gc;
+mod interrupt;
+mod module;
 mod native_functions;
+mod string_methods;
 
 use std::{
-    alloc::{self, Layout},
+    cell::Cell,
     fmt::Write as _,
+    mem,
     ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+    time::{Duration, Instant},
 };
 
+pub use builder::{VMBuilder, DEFAULT_GC_SLICE_BUDGET, DEFAULT_MAX_FRAMES, DEFAULT_MAX_STACK};
+pub use interrupt::InterruptHandle;
+
 use callframe::CallFrame;
 use miette::{LabeledSpan, NamedSource};
-use tracing::debug;
+use tracing::{debug, warn};
 
 use crate::{
-    datastructures::hash_table::HashTable,
+    datastructures::{hash_table::HashTable, memory},
     error::InterpreterError,
-    gc::Gc,
+    gc::{Gc, Node},
+    inline_cache::{CacheStats, CachedField, InlineCache},
     op::Op,
     parser::Parser,
     printer::{ConsolePrinter, Printer},
     types::{
-        bound_method::BoundMethod, class::Class, closure::Closure, instance::Instance, obj::Obj,
-        obj_ref::ObjRef, value::Value,
+        bound_method::BoundMethod, class::Class, closure::Closure, instance::Instance,
+        module::Module, obj::Obj, obj_ref::ObjRef, shape::Shape, string::LoxString, value::Value,
     },
 };
 
-const FRAMES_MAX: usize = 64;
-const STACK_MAX: usize = 256 * FRAMES_MAX; // us::
+const STACK_INITIAL: usize = 256;
 
 pub struct VM {
     stack: *mut Value,
     stack_top: *mut Value,
+    stack_capacity: usize,
+    max_stack: usize,
     frames: *mut CallFrame,
     frame_count: usize,
+    frames_capacity: usize,
+    max_frames: usize,
     gc: Gc,
-    globals: HashTable,
+    gc_slice_budget: usize,
+    gc_bytes_before: usize,
+    // natives and the prelude, visible in every module
+    builtins: HashTable,
+    string_methods: HashTable,
+    list_methods: HashTable,
+    main_module: ObjRef,
+    // imported modules by their canonical path
+    modules: HashTable,
     printer: Box<dyn Printer>,
     sources: Vec<NamedSource<String>>,
     open_upvalues: Option<ObjRef>,
     init_string: Value,
+    empty_shape: ObjRef,
+    error_class: Value,
+    cache_stats: CacheStats,
+    running_finalizers: bool,
+    instructions_executed: u64,
+    max_instructions: Option<u64>,
+    instruction_limit: Option<u64>,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    next_deadline_check: u64,
+    interrupt: InterruptHandle,
 }
 
 struct UpvalueLocation {
@@ -53,7 +88,7 @@ macro_rules! binary_operator {
             if let (Value::Number(a), Value::Number(b)) = ($self.peek(1), $self.peek(0)) {
                 let _ = $self.pop();
                 let _ = $self.pop();
-                $self.push($constructor(a $op b));
+                $self.push($constructor(a $op b))?;
             } else {
                 miette::bail!(
                     labels = vec![LabeledSpan::at($self.current_frame().current_location(), "here")],
@@ -64,6 +99,15 @@ macro_rules! binary_operator {
     };
 }
 
+fn floored_modulo(a: f64, b: f64) -> f64 {
+    let remainder = a % b;
+    if remainder != 0.0 && (remainder < 0.0) != (b < 0.0) {
+        remainder + b
+    } else {
+        remainder
+    }
+}
+
 macro_rules! ip {
     ($self: ident) => {
         (*$self.frames.add($self.frame_count - 1)).ip
@@ -71,28 +115,75 @@ macro_rules! ip {
 }
 
 impl VM {
-    pub fn new() -> Self {
-        //Safety: Layouts are guaranteed to be nonzero sized.
-        let stack =
-            unsafe { alloc::alloc(Layout::array::<Value>(STACK_MAX).unwrap()) as *mut Value };
-        let frames =
-            unsafe { alloc::alloc(Layout::array::<Value>(FRAMES_MAX).unwrap()) as *mut CallFrame };
+    pub fn builder() -> VMBuilder {
+        VMBuilder::new()
+    }
+
+    fn from_builder(builder: VMBuilder) -> Self {
+        let VMBuilder {
+            max_frames,
+            max_stack,
+            gc_slice_budget,
+            max_heap_size,
+            max_instructions,
+            timeout,
+            file_access,
+        } = builder;
+        let stack = memory::alloc_array(STACK_INITIAL).as_ptr();
+        let frames_capacity = memory::grow_capacity(0).min(max_frames);
+        let frames = memory::alloc_array(frames_capacity).as_ptr();
         let mut gc = Gc::new();
         let init_string = Value::Obj(gc.alloc("init"));
-        let globals = HashTable::new();
+        let empty_shape = gc.alloc(Obj::Shape(Shape::new()));
+        let main_module = gc.alloc(Obj::Module(Module::new(
+            LoxString::from_str("main"),
+            Value::Nil,
+        )));
         let mut vm = Self {
             stack,
             stack_top: stack,
+            stack_capacity: STACK_INITIAL,
+            max_stack,
             frames,
             frame_count: 0,
+            frames_capacity,
+            max_frames,
             gc,
-            globals,
+            gc_slice_budget,
+            gc_bytes_before: 0,
+            builtins: HashTable::new(),
+            string_methods: HashTable::new(),
+            list_methods: HashTable::new(),
+            main_module,
+            modules: HashTable::new(),
             printer: Box::new(ConsolePrinter),
             sources: vec![],
             open_upvalues: None,
             init_string,
+            empty_shape,
+            error_class: Value::Nil,
+            cache_stats: CacheStats::default(),
+            running_finalizers: false,
+            instructions_executed: 0,
+            max_instructions: None,
+            instruction_limit: None,
+            timeout: None,
+            deadline: None,
+            next_deadline_check: 0,
+            interrupt: InterruptHandle::new(),
         };
         vm.define_native_functions();
+        if file_access {
+            vm.define_file_natives();
+        }
+        vm.define_string_methods();
+        vm.define_error_class();
+        // natives and the prelude are defined before, so they can not run out of memory or time
+        if let Some(max_heap_size) = max_heap_size {
+            vm.gc.set_max_heap_size(max_heap_size);
+        }
+        vm.max_instructions = max_instructions;
+        vm.timeout = timeout;
         vm
     }
 
@@ -100,42 +191,105 @@ impl VM {
         self.gc.heapdump()
     }
 
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache_stats
+    }
+
     pub fn interpret(
         &mut self,
         src: NamedSource<String>,
     ) -> std::result::Result<(), InterpreterError> {
+        self.interpret_in(src, self.main_module)
+    }
+
+    // runs the script with the globals of the module
+    fn interpret_in(
+        &mut self,
+        src: NamedSource<String>,
+        module: ObjRef,
+    ) -> std::result::Result<(), InterpreterError> {
+        // a script stopped by its budget is abandoned
+        self.reset_stack();
         let function = match Parser::compile(&src, &mut self.gc) {
             Ok(c) => c,
             Err(e) => return Err(InterpreterError::CompileError(e.with_source_code(src))),
         };
 
         let function = self.gc.alloc(function); // gc.alloc to prevent collection
-        self.push(Value::Obj(function));
-        let closure = self.alloc(Obj::Closure(Closure::new(function, vec![])));
-        self.pop();
-        self.push(Value::Obj(closure));
-
         let arg_count = 0;
-        self.call_value(self.peek(arg_count), 0)
-            .map_err(|e| InterpreterError::RuntimeError {
-                error: e.with_source_code(src.clone()),
-                stacktrace: self.stacktrace(),
+        self.push(Value::Obj(module))
+            .and_then(|()| self.push(Value::Obj(function)))
+            .and_then(|()| self.alloc(Obj::Closure(Closure::new(function, vec![], module))))
+            .and_then(|closure| {
+                self.pop();
+                self.pop();
+                self.push(Value::Obj(closure))?;
+                self.call_value(self.peek(arg_count), 0)
+            })
+            .map_err(|e| {
+                let stacktrace = self.stacktrace();
+                self.reset_stack();
+                InterpreterError::RuntimeError {
+                    error: e.with_source_code(src.clone()),
+                    stacktrace,
+                }
             })?;
 
         self.sources.push(src);
+        self.set_instruction_budget(self.max_instructions);
+        self.set_timeout(self.timeout);
+        self.clear_interrupt();
+        self.run()
+    }
 
-        match self.interpret_inner() {
-            Ok(value) => Ok(value),
-            Err(e) => {
-                let stacktrace = self.stacktrace();
-                self.reset_stack();
-                let error = e.with_source_code(self.current_frame().chunk().source.clone());
-                Err(InterpreterError::RuntimeError { error, stacktrace })
-            }
+    /// Continues a script stopped by an exhausted instruction budget or an exceeded deadline.
+    /// Does nothing if no script is stopped.
+    pub fn resume(&mut self) -> std::result::Result<(), InterpreterError> {
+        if self.frame_count == 0 {
+            return Ok(());
+        }
+        self.run()
+    }
+
+    fn run(&mut self) -> std::result::Result<(), InterpreterError> {
+        let result = self.interpret_inner(0);
+        let result = result.and_then(|()| self.run_finalizers());
+        let cache_stats = self.cache_stats();
+        debug!(
+            "inline caches: {} hits, {} misses, hit rate {:.2}",
+            cache_stats.hits,
+            cache_stats.misses,
+            cache_stats.hit_rate()
+        );
+        match result {
+            Ok(()) => Ok(()),
+            // the stack is kept to allow resuming
+            Err(e) => match e.downcast::<InterpreterError>() {
+                Ok(stopped) => Err(stopped),
+                // raised by a finalizer run after the script returned
+                Err(error) if self.frame_count == 0 => Err(InterpreterError::RuntimeError {
+                    error,
+                    stacktrace: String::new(),
+                }),
+                Err(e) => {
+                    let stacktrace = self.stacktrace();
+                    let error = e.with_source_code(self.current_frame().chunk().source.clone());
+                    self.reset_stack();
+                    Err(InterpreterError::RuntimeError { error, stacktrace })
+                }
+            },
+        }
+    }
+
+    // runs until the frame at base_frame returns
+    fn interpret_inner(&mut self, base_frame: usize) -> miette::Result<()> {
+        while let Err(error) = self.execute(base_frame) {
+            self.catch(error, base_frame)?;
         }
+        Ok(())
     }
 
-    fn interpret_inner(&mut self) -> miette::Result<()> {
+    fn execute(&mut self, base_frame: usize) -> miette::Result<()> {
         loop {
             let op = unsafe { *ip!(self) };
             debug!("{}", self.current_frame().disassemble_at_current_index());
@@ -143,31 +297,33 @@ impl VM {
             unsafe {
                 ip!(self) = ip!(self).add(1);
             }
+            self.instructions_executed += 1;
             match op {
                 Op::Return => {
                     let result = self.pop();
                     let slots = self.current_frame().slots;
                     self.close_upvalues(slots);
                     self.frame_count -= 1;
-                    if self.frame_count == 0 {
-                        self.pop();
+                    if self.frame_count == base_frame {
+                        // the result is discarded together with the callee
+                        self.stack_top = slots;
                         return Ok(());
                     }
                     self.stack_top = slots;
-                    self.push(result);
+                    self.push(result)?;
                 }
                 Op::Constant(index) => {
                     let constant = self.current_frame().chunk().constants[index as usize];
-                    self.push(constant);
+                    self.push(constant)?;
                 }
-                Op::Nil => self.push(Value::Nil),
-                Op::True => self.push(Value::Boolean(true)),
-                Op::False => self.push(Value::Boolean(false)),
+                Op::Nil => self.push(Value::Nil)?,
+                Op::True => self.push(Value::Boolean(true))?,
+                Op::False => self.push(Value::Boolean(false))?,
                 Op::Negate => {
                     let peek = self.peek(0);
                     if let Value::Number(number) = peek {
                         let _ = self.pop();
-                        self.push(Value::Number(-number));
+                        self.push(Value::Number(-number))?;
                     } else {
                         miette::bail!(
                             labels = vec![LabeledSpan::at(
@@ -179,17 +335,30 @@ impl VM {
                     }
                 }
                 Op::Add => self.plus_operator()?,
+                Op::BuildString(count) => self.build_string(count)?,
                 Op::Subtract => binary_operator!(self, -, Value::Number),
                 Op::Multiply => binary_operator!(self, *, Value::Number),
                 Op::Divide => binary_operator!(self, /, Value::Number),
+                Op::Modulo => self.number_operator(floored_modulo)?,
+                Op::FloorDivide => self.number_operator(|a, b| (a / b).floor())?,
+                Op::Power => self.number_operator(f64::powf)?,
+                Op::BitAnd => self.integer_operator(|a, b| Some(a & b))?,
+                Op::BitOr => self.integer_operator(|a, b| Some(a | b))?,
+                Op::BitXor => self.integer_operator(|a, b| Some(a ^ b))?,
+                Op::ShiftLeft => self.integer_operator(|a, b| {
+                    u32::try_from(b).ok().and_then(|b| a.checked_shl(b))
+                })?,
+                Op::ShiftRight => self.integer_operator(|a, b| {
+                    u32::try_from(b).ok().and_then(|b| a.checked_shr(b))
+                })?,
                 Op::Not => {
                     let pop: Value = self.pop();
-                    self.push(Value::Boolean(pop.is_falsey()))
+                    self.push(Value::Boolean(pop.is_falsey()))?;
                 }
                 Op::Equal => {
                     let b = self.pop();
                     let a = self.pop();
-                    self.push(Value::Boolean(a == b));
+                    self.push(Value::Boolean(a == b))?;
                 }
                 Op::Greater => binary_operator!(self, >, Value::Boolean),
                 Op::Less => binary_operator!(self, <, Value::Boolean),
@@ -200,15 +369,38 @@ impl VM {
                 Op::Pop => {
                     self.pop();
                 }
+                Op::Dup(distance) => self.push(self.peek(distance))?,
+                Op::Bury(count) => {
+                    let top = self.peek(0);
+                    // SAFETY: the compiler buries only below values it pushed
+                    unsafe {
+                        let base = self.stack_top.sub(1 + count as usize);
+                        ptr::copy(base, base.add(1), count as usize);
+                        *base = top;
+                    }
+                }
                 Op::DefineGlobal(index) => {
                     let name = self.current_frame().chunk().constants[index as usize];
-                    self.globals.insert(name, self.peek(0));
+                    let mut module = self.current_frame().module();
+                    self.reserve(module.as_module().globals.growth())?;
+                    let globals = &mut module.as_module_mut().globals;
+                    let before = globals.allocated_bytes();
+                    globals.insert(name, self.peek(0));
+                    self.gc.charge(before, globals.allocated_bytes());
+                    // the name may be a new key as well
+                    self.gc.remember(module);
                     self.pop();
                 }
                 Op::GetGlobal(index) => {
                     let name = self.current_frame().chunk().constants[index as usize];
-                    if let Some(v) = self.globals.get(name) {
-                        self.push(v)
+                    let module = self.current_frame().module();
+                    if let Some(v) = module
+                        .as_module()
+                        .globals
+                        .get(name)
+                        .or_else(|| self.builtins.get(name))
+                    {
+                        self.push(v)?;
                     } else {
                         miette::bail!(
                             labels = vec![LabeledSpan::at(
@@ -222,9 +414,14 @@ impl VM {
                 }
                 Op::SetGlobal(index) => {
                     let name = self.current_frame().chunk().constants[index as usize];
-                    let inserted = self.globals.insert(name, self.peek(0));
+                    let mut module = self.current_frame().module();
+                    self.reserve(module.as_module().globals.growth())?;
+                    let globals = &mut module.as_module_mut().globals;
+                    let before = globals.allocated_bytes();
+                    let inserted = globals.insert(name, self.peek(0));
+                    self.gc.charge(before, globals.allocated_bytes());
                     if inserted {
-                        self.globals.delete(name);
+                        globals.delete(name);
                         miette::bail!(
                             labels = vec![LabeledSpan::at(
                                 self.current_frame().current_location(),
@@ -234,10 +431,12 @@ impl VM {
                             name
                         )
                     }
+                    // the name is an existing key, names are interned
+                    self.gc.write_barrier(module, self.peek(0));
                 }
                 Op::GetLocal(slot) => unsafe {
                     let slots = self.current_frame().slots;
-                    self.push(*(slots.add(slot as usize)));
+                    self.push(*(slots.add(slot as usize)))?;
                 },
                 Op::SetLocal(slot) => unsafe {
                     let slots = self.current_frame().slots;
@@ -246,7 +445,7 @@ impl VM {
                 Op::GetUpvalue(index) => unsafe {
                     let upvalue = self.current_frame().upvalues()[index as usize];
                     if let Obj::Upvalue { location, .. } = upvalue.deref() {
-                        self.push(**location);
+                        self.push(**location)?;
                     } else {
                         unreachable!()
                     }
@@ -254,50 +453,83 @@ impl VM {
                 Op::SetUpvalue(index) => unsafe {
                     let upvalue = self.current_frame().upvalues()[index as usize];
                     if let Obj::Upvalue { location, .. } = upvalue.deref() {
-                        **location = self.peek(0)
+                        **location = self.peek(0);
+                        self.gc.write_barrier(upvalue, self.peek(0));
                     } else {
                         unreachable!()
                     }
                 },
-                Op::GetProperty(index) => self.get_property(index)?,
-                Op::SetProperty(index) => self.set_property(index)?,
+                Op::GetProperty {
+                    property_index,
+                    cache,
+                } => self.get_property(property_index, cache)?,
+                Op::SetProperty {
+                    property_index,
+                    cache,
+                } => self.set_property(property_index, cache)?,
                 Op::JumpIfFalse(offset) => {
                     if self.peek(0).is_falsey() {
                         unsafe { ip!(self) = ip!(self).add((offset - 1) as usize) }
                     }
                 }
+                Op::JumpIfNil(offset) => {
+                    if self.peek(0) == Value::Nil {
+                        unsafe { ip!(self) = ip!(self).add((offset - 1) as usize) }
+                    }
+                }
                 Op::Jump(offset) => unsafe {
                     ip!(self) = ip!(self).add((offset - 1) as usize);
                 },
-                Op::Loop(offset) => unsafe {
-                    ip!(self) = ip!(self).sub((offset + 1) as usize);
-                },
+                Op::Loop(offset) => {
+                    self.check_interrupt()?;
+                    unsafe { ip!(self) = ip!(self).sub((offset + 1) as usize) };
+                    self.safepoint()?
+                }
                 Op::Call(arg_count) => {
+                    self.check_interrupt()?;
                     let callee = self.peek(arg_count);
-                    self.call_value(callee, arg_count)?
+                    self.call_value(callee, arg_count)?;
+                    self.safepoint()?
+                }
+                Op::TailCall(arg_count) => {
+                    self.check_interrupt()?;
+                    let callee = self.peek(arg_count);
+                    let frame_count = self.frame_count;
+                    self.call_value(callee, arg_count)?;
+                    // natives and classes without initializer did not push a frame, the following Return handles them
+                    if self.frame_count > frame_count {
+                        self.replace_caller_frame();
+                    }
+                    self.safepoint()?
                 }
                 Op::Closure(index) => {
-                    self.handle_closure(index);
+                    self.handle_closure(index)?;
                 }
                 Op::CloseUpvalue => unsafe {
                     self.close_upvalues(self.stack_top.sub(1));
                     self.pop();
                 },
-                Op::Class(index) => self.create_class(index),
+                Op::Class(index) => self.create_class(index)?,
                 Op::Method(index) => self.define_method(index),
+                Op::StaticMethod(index) => self.define_static_method(index),
                 Op::Invoke {
                     property_index,
                     arg_count,
+                    cache,
                 } => {
+                    self.check_interrupt()?;
                     let constant = self.current_frame().chunk().constants[property_index as usize];
-                    self.invoke(constant, arg_count)?
+                    self.invoke(constant, arg_count, cache)?;
+                    self.safepoint()?
                 }
                 Op::SuperInvoke {
                     property_index,
                     arg_count,
                 } => {
+                    self.check_interrupt()?;
                     let constant = self.current_frame().chunk().constants[property_index as usize];
-                    self.super_invoke(constant, arg_count)?
+                    self.super_invoke(constant, arg_count)?;
+                    self.safepoint()?
                 }
                 Op::Inherit => {
                     let superclass = self.peek(1);
@@ -305,7 +537,10 @@ impl VM {
                         if let Obj::Class(superclass) = obj.deref() {
                             let mut peek = self.peek(0);
                             let subclass = peek.as_class_mut();
+                            let before = subclass.allocated_bytes();
                             subclass.copy_methods(superclass);
+                            self.gc.charge(before, subclass.allocated_bytes());
+                            self.gc.remember(*peek.as_obj());
                             self.pop(); // pop subclass
                             continue;
                         }
@@ -325,6 +560,60 @@ impl VM {
                     let superclass = superclass.as_class();
                     self.bind_method(superclass, name)?;
                 }
+                Op::Index => self.index()?,
+                Op::SetIndex => self.set_index()?,
+                Op::Import(index) => self.import(index)?,
+                Op::Throw => return Err(self.thrown()),
+                Op::EndFinally(slot) => unsafe {
+                    let slots = self.current_frame().slots;
+                    let kind = *slots.add(slot as usize);
+                    let value = *slots.add(slot as usize + 1);
+                    match kind {
+                        Value::Nil => {}
+                        Value::Boolean(true) => {
+                            self.push(value)?;
+                            return Err(self.thrown());
+                        }
+                        // skips the jump over the return
+                        _ => {
+                            self.push(value)?;
+                            ip!(self) = ip!(self).add(1);
+                        }
+                    }
+                },
+                Op::AddLocals { left, right } => unsafe {
+                    let slots = self.current_frame().slots;
+                    let (a, b) = (*slots.add(left as usize), *slots.add(right as usize));
+                    if let (Value::Number(a), Value::Number(b)) = (a, b) {
+                        self.push(Value::Number(a + b))?;
+                    } else {
+                        self.push(a)?;
+                        self.push(b)?;
+                        self.plus_operator()?
+                    }
+                },
+                Op::JumpIfLocalNotLess {
+                    slot,
+                    constant,
+                    offset,
+                } => {
+                    let value = unsafe { *self.current_frame().slots.add(slot as usize) };
+                    let constant = self.current_frame().chunk().constants[constant as usize];
+                    if let (Value::Number(a), Value::Number(b)) = (value, constant) {
+                        let less = a < b;
+                        if !less {
+                            unsafe { ip!(self) = ip!(self).add((offset - 1) as usize) }
+                        }
+                    } else {
+                        miette::bail!(
+                            labels = vec![LabeledSpan::at(
+                                self.current_frame().current_location(),
+                                "here"
+                            )],
+                            "Operands for operation must be both be numbers"
+                        );
+                    }
+                }
             }
         }
     }
@@ -333,25 +622,86 @@ impl VM {
         let name = self.current_frame().chunk().constants[index as usize];
         let method = self.peek(0);
         let mut peek = self.peek(1);
-        peek.as_class_mut().add_method(name, method);
+        let class = peek.as_class_mut();
+        let before = class.allocated_bytes();
+        class.add_method(name, method);
+        self.gc.charge(before, class.allocated_bytes());
+        self.gc.write_barrier(*peek.as_obj(), method);
+        self.pop();
+    }
+
+    fn define_static_method(&mut self, index: u8) {
+        let name = self.current_frame().chunk().constants[index as usize];
+        let method = self.peek(0);
+        let mut peek = self.peek(1);
+        let class = peek.as_class_mut();
+        let before = class.allocated_bytes();
+        class.add_static_method(name, method);
+        self.gc.charge(before, class.allocated_bytes());
+        self.gc.write_barrier(*peek.as_obj(), method);
         self.pop();
     }
 
+    // fields shadow static methods like fields of instances shadow methods
+    fn get_static_property(&mut self, class: &Class, name: Value) -> miette::Result<()> {
+        if let Some(field) = class.get_static_field(name) {
+            self.pop();
+            self.push(field)?;
+            Ok(())
+        } else if let Some(method) = class.get_static_method(name) {
+            self.bind(method)
+        } else {
+            Err(self.undefined_static_property(class, name))
+        }
+    }
+
+    fn invoke_static(&mut self, class: &Class, name: Value, arg_count: u8) -> miette::Result<()> {
+        if let Some(field) = class.get_static_field(name) {
+            unsafe { *(self.stack_top.sub(arg_count as usize).sub(1)) = field };
+            self.call_value(field, arg_count)
+        } else if let Some(method) = class.get_static_method(name) {
+            self.call(arg_count, *method.as_obj(), method.as_closure())
+        } else {
+            Err(self.undefined_static_property(class, name))
+        }
+    }
+
+    fn undefined_static_property(&mut self, class: &Class, name: Value) -> miette::Report {
+        miette::miette!(
+            labels = vec![LabeledSpan::at(
+                self.current_frame().current_location(),
+                "here"
+            )],
+            "Undefined property {} on class {}",
+            name,
+            class.name()
+        )
+    }
+
     fn super_invoke(&mut self, name: Value, arg_count: u8) -> Result<(), miette::Error> {
         let super_class = self.pop();
         let super_class = super_class.as_class();
         self.invoke_from_class(super_class, name, arg_count)
     }
-    fn invoke(&mut self, name: Value, arg_count: u8) -> Result<(), miette::Error> {
+    fn invoke(&mut self, name: Value, arg_count: u8, cache: u16) -> Result<(), miette::Error> {
         let receiver = self.peek(arg_count);
         if let Value::Obj(obj) = receiver {
             if let Obj::Instance(instance) = obj.deref() {
-                if let Some(value) = instance.get_field(name) {
+                if let Some(value) = self.find_field(instance, name, cache) {
                     unsafe { *(self.stack_top.sub(arg_count as usize).sub(1)) = value };
                     self.call_value(value, arg_count)
                 } else {
-                    self.invoke_from_class(instance.class(), name, arg_count)
+                    let method = self.find_method(instance.class_obj(), name, cache)?;
+                    self.call(arg_count, *method.as_obj(), method.as_closure())
                 }
+            } else if let Obj::Module(module) = obj.deref() {
+                let value = self.module_variable(module, name)?;
+                unsafe { *(self.stack_top.sub(arg_count as usize).sub(1)) = value };
+                self.call_value(value, arg_count)
+            } else if let Obj::Class(class) = obj.deref() {
+                self.invoke_static(class, name, arg_count)
+            } else if let Some(method) = self.builtin_method(obj, name)? {
+                self.call_value(method, arg_count)
             } else {
                 miette::bail!(
                     labels = vec![LabeledSpan::at(
@@ -396,7 +746,7 @@ impl VM {
         self.call(arg_count, *method.as_obj(), method.as_closure())
     }
 
-    fn get_property(&mut self, index: u8) -> Result<(), miette::Error> {
+    fn get_property(&mut self, index: u8, cache: u16) -> Result<(), miette::Error> {
         let name = self.current_frame().chunk().constants[index as usize];
         let obj = if let Value::Obj(obj) = self.peek(0) {
             obj
@@ -410,6 +760,18 @@ impl VM {
                 self.peek(0)
             )
         };
+        if let Obj::Module(module) = obj.deref() {
+            let value = self.module_variable(module, name)?;
+            self.pop();
+            self.push(value)?;
+            return Ok(());
+        }
+        if let Some(method) = self.builtin_method(obj, name)? {
+            return self.bind(method);
+        }
+        if let Obj::Class(class) = obj.deref() {
+            return self.get_static_property(class, name);
+        }
         let instance = if let Obj::Instance(instance) = obj.deref() {
             instance
         } else {
@@ -423,16 +785,17 @@ impl VM {
             )
         };
 
-        if let Some(field) = instance.get_field(name) {
+        if let Some(field) = self.find_field(instance, name, cache) {
             self.pop();
-            self.push(field);
+            self.push(field)?;
             Ok(())
         } else {
-            self.bind_method(instance.class(), name)
+            let method = self.find_method(instance.class_obj(), name, cache)?;
+            self.bind(method)
         }
     }
 
-    fn set_property(&mut self, index: u8) -> Result<(), miette::Error> {
+    fn set_property(&mut self, index: u8, cache: u16) -> Result<(), miette::Error> {
         let name = self.current_frame().chunk().constants[index as usize];
         let mut obj = if let Value::Obj(obj) = self.peek(1) {
             obj
@@ -447,10 +810,57 @@ impl VM {
             )
         };
         if let Obj::Instance(instance) = obj.deref_mut() {
-            instance.set_field(name, self.peek(0));
+            let value = self.peek(0);
+            let shape = instance.shape();
+            let closure = self.current_frame().closure;
+            let cache = Self::inline_cache(&closure, cache);
+            let mut entry = cache.get();
+            match entry.field(shape) {
+                Some(CachedField::Slot(slot)) => {
+                    self.cache_stats.hits += 1;
+                    instance.set_field_at(slot, value);
+                }
+                Some(CachedField::Transition(next)) => {
+                    self.cache_stats.hits += 1;
+                    self.reserve(instance.field_growth())?;
+                    let before = instance.allocated_bytes();
+                    instance.add_field(next, value);
+                    self.gc.charge(before, instance.allocated_bytes());
+                    self.gc.write_barrier(obj, Value::Obj(next));
+                }
+                _ => {
+                    self.cache_stats.misses += 1;
+                    if let Some(slot) = shape.as_shape().slot(name) {
+                        instance.set_field_at(slot, value);
+                        entry.update_field(shape, CachedField::Slot(slot));
+                    } else {
+                        let next = self.shape_transition(shape, name)?;
+                        self.reserve(instance.field_growth())?;
+                        let before = instance.allocated_bytes();
+                        instance.add_field(next, value);
+                        self.gc.charge(before, instance.allocated_bytes());
+                        self.gc.write_barrier(obj, Value::Obj(next));
+                        entry.update_field(shape, CachedField::Transition(next));
+                    }
+                    self.update_inline_cache(&closure, cache, entry);
+                }
+            }
+            self.gc.write_barrier(obj, value);
+            let value = self.pop();
+            self.pop();
+            self.push(value)?;
+            Ok(())
+        } else if let Obj::Class(class) = obj.deref_mut() {
+            let value = self.peek(0);
+            self.reserve(class.static_field_growth())?;
+            let before = class.allocated_bytes();
+            class.set_static_field(name, value);
+            self.gc.charge(before, class.allocated_bytes());
+            // the name may be a new key as well
+            self.gc.remember(obj);
             let value = self.pop();
             self.pop();
-            self.push(value);
+            self.push(value)?;
             Ok(())
         } else {
             miette::bail!(
@@ -464,6 +874,89 @@ impl VM {
         }
     }
 
+    fn find_field(&mut self, instance: &Instance, name: Value, cache: u16) -> Option<Value> {
+        let shape = instance.shape();
+        let closure = self.current_frame().closure;
+        let cache = Self::inline_cache(&closure, cache);
+        let mut entry = cache.get();
+        let field = if let Some(field) = entry.field(shape) {
+            self.cache_stats.hits += 1;
+            field
+        } else {
+            self.cache_stats.misses += 1;
+            let field = shape
+                .as_shape()
+                .slot(name)
+                .map_or(CachedField::Missing, CachedField::Slot);
+            entry.update_field(shape, field);
+            self.update_inline_cache(&closure, cache, entry);
+            field
+        };
+        if let CachedField::Slot(slot) = field {
+            Some(instance.get_field_at(slot))
+        } else {
+            None
+        }
+    }
+
+    fn shape_transition(&mut self, shape: ObjRef, name: Value) -> miette::Result<ObjRef> {
+        if let Some(next) = shape.as_shape().transition(name) {
+            return Ok(next);
+        }
+        let next = Obj::Shape(shape.as_shape().with_field(name));
+        let next = self.alloc(next)?;
+        self.push(Value::Obj(next))?;
+        self.reserve(shape.as_shape().transition_growth())?;
+        self.pop();
+        let mut shape = shape;
+        let before = shape.as_shape().allocated_bytes();
+        shape.as_shape_mut().add_transition(name, next);
+        self.gc.charge(before, shape.as_shape().allocated_bytes());
+        self.gc.write_barrier(shape, Value::Obj(next));
+        Ok(next)
+    }
+
+    fn find_method(&mut self, class: ObjRef, name: Value, cache: u16) -> miette::Result<Value> {
+        let closure = self.current_frame().closure;
+        let cache = Self::inline_cache(&closure, cache);
+        let mut entry = cache.get();
+        if let Some(method) = entry.method(class) {
+            self.cache_stats.hits += 1;
+            return Ok(method);
+        }
+        self.cache_stats.misses += 1;
+        if let Some(method) = class.as_class().get_method(name) {
+            entry.update_method(class, method);
+            self.update_inline_cache(&closure, cache, entry);
+            Ok(method)
+        } else {
+            miette::bail!(
+                labels = vec![LabeledSpan::at(
+                    self.current_frame().current_location(),
+                    "here"
+                )],
+                "Undefined property {} on class {}",
+                name,
+                class.as_class().name()
+            )
+        }
+    }
+
+    fn inline_cache(closure: &ObjRef, index: u16) -> &Cell<InlineCache> {
+        &closure.as_closure().function.as_function().chunk().caches[index as usize]
+    }
+
+    // caches live in the chunk of the function, the shapes, classes and methods they hold may be young
+    fn update_inline_cache(
+        &mut self,
+        closure: &ObjRef,
+        cache: &Cell<InlineCache>,
+        entry: InlineCache,
+    ) {
+        cache.set(entry);
+        self.gc.remember(closure.as_closure().function);
+    }
+
     fn bind_method(&mut self, class: &Class, name: Value) -> Result<(), miette::Error> {
         let method = if let Some(method) = class.get_method(name) {
             method
@@ -478,20 +971,24 @@ impl VM {
                 class.name()
             )
         };
+        self.bind(method)
+    }
 
+    fn bind(&mut self, method: Value) -> miette::Result<()> {
         let bound_method = BoundMethod::new(self.peek(0), *method.as_obj());
-        let bound_method = self.gc.alloc(Obj::BoundMethod(bound_method));
+        let bound_method = self.alloc(Obj::BoundMethod(bound_method))?;
         self.pop();
-        self.push(Value::Obj(bound_method));
+        self.push(Value::Obj(bound_method))?;
         Ok(())
     }
 
-    fn create_class(&mut self, index: u8) {
+    fn create_class(&mut self, index: u8) -> miette::Result<()> {
         let name = self.current_frame().chunk().constants[index as usize];
         let name = name.as_string();
         let class = Obj::Class(Class::new(name.clone()));
-        let class = self.gc.alloc(class);
-        self.push(Value::Obj(class));
+        let class = self.alloc(class)?;
+        self.push(Value::Obj(class))?;
+        Ok(())
     }
 
     fn close_upvalues(&mut self, last: *mut Value) {
@@ -516,10 +1013,11 @@ impl VM {
             } else {
                 unreachable!()
             }
+            self.gc.write_barrier(current, unsafe { *location });
         }
     }
 
-    fn handle_closure(&mut self, index: u8) {
+    fn handle_closure(&mut self, index: u8) -> miette::Result<()> {
         let obj = self.current_frame().chunk().constants[index as usize];
         let function = obj.as_function();
         let upvalues = function
@@ -530,16 +1028,18 @@ impl VM {
                     let value = unsafe { self.current_frame().slots.add(u.index() as usize) };
                     self.capture_upvalue(value)
                 } else {
-                    self.current_frame().upvalues()[u.index() as usize]
+                    Ok(self.current_frame().upvalues()[u.index() as usize])
                 }
             })
-            .collect();
-        let closure = Obj::Closure(Closure::new(*obj.as_obj(), upvalues));
-        let closure = self.alloc(closure);
-        self.push(Value::Obj(closure));
+            .collect::<miette::Result<_>>()?;
+        let module = self.current_frame().module();
+        let closure = Obj::Closure(Closure::new(*obj.as_obj(), upvalues, module));
+        let closure = self.alloc(closure)?;
+        self.push(Value::Obj(closure))?;
+        Ok(())
     }
 
-    fn capture_upvalue(&mut self, local: *mut Value) -> ObjRef {
+    fn capture_upvalue(&mut self, local: *mut Value) -> miette::Result<ObjRef> {
         let mut prev_upvalue: Option<ObjRef> = None;
         let mut upvalue = self.open_upvalues;
         while let Some(UpvalueLocation {
@@ -562,7 +1062,7 @@ impl VM {
         }) = Self::upvalue_location(upvalue)
         {
             if location == local {
-                return current;
+                return Ok(current);
             }
         }
 
@@ -571,7 +1071,7 @@ impl VM {
             next: upvalue,
             closed: Value::Nil,
         };
-        let created_upvalue = self.alloc(created_upvalue);
+        let created_upvalue = self.alloc(created_upvalue)?;
         if let Some(mut obj) = prev_upvalue {
             if let Obj::Upvalue { next, .. } = obj.deref_mut() {
                 *next = Some(created_upvalue);
@@ -581,7 +1081,7 @@ impl VM {
         } else {
             self.open_upvalues = Some(created_upvalue);
         }
-        created_upvalue
+        Ok(created_upvalue)
     }
 
     fn upvalue_location(upvalue: Option<ObjRef>) -> Option<UpvalueLocation> {
@@ -601,7 +1101,7 @@ impl VM {
     }
 
     fn call_value(&mut self, callee: Value, arg_count: u8) -> miette::Result<()> {
-        if self.frame_count == FRAMES_MAX {
+        if self.frame_count == self.max_frames || self.stack_len() > self.max_stack {
             miette::bail!(
                 labels = vec![LabeledSpan::at(
                     self.current_frame().current_location(),
@@ -624,14 +1124,17 @@ impl VM {
         match obj.deref() {
             Obj::Closure(closure) => self.call(arg_count, obj, closure),
             Obj::Native(function) => unsafe {
-                let result = function(arg_count, self.stack_top.sub(arg_count as usize), self);
+                let result = function(arg_count, self.stack_top.sub(arg_count as usize), self)?;
                 self.stack_top = self.stack_top.sub(arg_count as usize + 1);
-                self.push(result);
+                self.push(result)?;
                 Ok(())
             },
             Obj::Class(class) => unsafe {
-                let instance = Obj::Instance(Instance::new(obj));
-                let instance = self.gc.alloc(instance);
+                let instance = Obj::Instance(Instance::new(obj, self.empty_shape));
+                let instance = self.alloc(instance)?;
+                if class.has_finalizer() {
+                    self.gc.register_finalizer(instance);
+                }
                 *self.stack_top.sub(arg_count as usize).sub(1) = Value::Obj(instance);
                 if let Some(initializer) = class.get_method(self.init_string) {
                     self.call(arg_count, *initializer.as_obj(), initializer.as_closure())?;
@@ -651,11 +1154,11 @@ impl VM {
                 unsafe {
                     *self.stack_top.sub(arg_count as usize).sub(1) = bound_method.receiver();
                 }
-                self.call(
-                    arg_count,
-                    bound_method.method(),
-                    bound_method.method().as_closure(),
-                )
+                match bound_method.method().deref() {
+                    Obj::Closure(closure) => self.call(arg_count, bound_method.method(), closure),
+                    // methods of strings and lists
+                    _ => self.call_value(Value::Obj(bound_method.method()), arg_count),
+                }
             }
             _ => miette::bail!(
                 labels = vec![LabeledSpan::at(
@@ -680,29 +1183,132 @@ impl VM {
                 arg_count
             )
         }
+        if self.frame_count == self.frames_capacity {
+            self.grow_frames();
+        }
         unsafe {
             let frame = self.frames.add(self.frame_count);
             (*frame).closure = obj;
             (*frame).ip = function.chunk().code.ptr();
             (*frame).slots = self.stack_top.sub(arg_count as usize + 1);
+            (*frame).tail_call = false;
         }
         self.frame_count += 1;
         Ok(())
     }
 
+    // moves the topmost frame and its callee and arguments down into the frame below it
+    fn replace_caller_frame(&mut self) {
+        unsafe {
+            let caller = self.frames.add(self.frame_count - 2);
+            let callee = self.frames.add(self.frame_count - 1);
+            let slots = (*caller).slots;
+            self.close_upvalues(slots);
+            let count = self.stack_top.offset_from((*callee).slots) as usize;
+            ptr::copy((*callee).slots, slots, count);
+            self.stack_top = slots.add(count);
+            (*caller).closure = (*callee).closure;
+            (*caller).ip = (*callee).ip;
+            (*caller).slots = slots;
+            (*caller).tail_call = true;
+        }
+        self.frame_count -= 1;
+    }
+
+    // the values stay on the stack until the string is allocated
+    fn build_string(&mut self, count: u8) -> miette::Result<()> {
+        // other values are displayed in a few bytes
+        let projected: usize = (0..count)
+            .filter_map(|distance| match self.peek(distance) {
+                Value::Obj(obj) if matches!(*obj, Obj::String(_)) => {
+                    Some(obj.as_string().string.len())
+                }
+                _ => None,
+            })
+            .sum();
+        self.reserve(size_of::<Node>() + projected)?;
+        let mut string = String::new();
+        for distance in (0..count).rev() {
+            write!(string, "{}", self.peek(distance)).expect("writing to a String");
+        }
+        let string = self.alloc(string)?;
+        self.stack_top = unsafe { self.stack_top.sub(count as usize) };
+        self.push(Value::Obj(string))?;
+        Ok(())
+    }
+
+    fn number_operator(&mut self, op: fn(f64, f64) -> f64) -> miette::Result<()> {
+        if let (Value::Number(a), Value::Number(b)) = (self.peek(1), self.peek(0)) {
+            self.pop();
+            self.pop();
+            self.push(Value::Number(op(a, b)))?;
+            Ok(())
+        } else {
+            miette::bail!(
+                labels = vec![LabeledSpan::at(
+                    self.current_frame().current_location(),
+                    "here"
+                )],
+                "Operands for operation must be both be numbers"
+            );
+        }
+    }
+
+    // the operands have to be integers which a number represents exactly, the result is
+    // rounded like any number beyond 2^53. None for shifts by a negative or too large count
+    fn integer_operator(&mut self, op: fn(i64, i64) -> Option<i64>) -> miette::Result<()> {
+        const MAX_EXACT: f64 = (1u64 << 53) as f64;
+        let (Value::Number(a), Value::Number(b)) = (self.peek(1), self.peek(0)) else {
+            miette::bail!(
+                labels = vec![LabeledSpan::at(
+                    self.current_frame().current_location(),
+                    "here"
+                )],
+                "Operands for operation must be both be numbers"
+            );
+        };
+        let is_integer = |n: f64| n.fract() == 0.0 && n.abs() <= MAX_EXACT;
+        if !is_integer(a) || !is_integer(b) {
+            miette::bail!(
+                labels = vec![LabeledSpan::at(
+                    self.current_frame().current_location(),
+                    "here"
+                )],
+                "Operands for bitwise operation must be integers up to 2^53, got {} and {}",
+                a,
+                b
+            );
+        }
+        let Some(result) = op(a as i64, b as i64) else {
+            miette::bail!(
+                labels = vec![LabeledSpan::at(
+                    self.current_frame().current_location(),
+                    "here"
+                )],
+                "Shift count must be between 0 and 63, got {}",
+                b
+            );
+        };
+        self.pop();
+        self.pop();
+        self.push(Value::Number(result as f64))?;
+        Ok(())
+    }
+
     fn plus_operator(&mut self) -> miette::Result<()> {
         match (self.peek(1), self.peek(0)) {
             (Value::Number(a), Value::Number(b)) => {
                 self.pop();
                 self.pop();
-                self.push(Value::Number(a + b));
+                self.push(Value::Number(a + b))?;
             }
             (Value::Obj(a), Value::Obj(b)) => {
                 if let (Obj::String(a), Obj::String(b)) = (a.deref(), b.deref()) {
+                    self.reserve(size_of::<Node>() + a.string.len() + b.string.len())?;
                     self.pop();
                     self.pop();
-                    let concated = self.alloc(a.string.to_owned() + &b.string);
-                    self.push(Value::Obj(concated));
+                    let concated = self.alloc(a.string.to_owned() + &b.string)?;
+                    self.push(Value::Obj(concated))?;
                 } else {
                     miette::bail!(
                         labels = vec![LabeledSpan::at(
@@ -724,15 +1330,89 @@ impl VM {
         Ok(())
     }
 
-    fn push(&mut self, value: Value) {
-        // SAFETY: we have mut access to self and therefore to the stack
-        unsafe { *self.stack_top = value };
-        // SAFETY: NOT SAFE, stack could overflow
-        unsafe { self.stack_top = self.stack_top.add(1) };
+    fn grow_frames(&mut self) {
+        let old_capacity = self.frames_capacity;
+        self.frames_capacity = memory::grow_capacity(old_capacity).min(self.max_frames);
+        // SAFETY: frames was allocated with old_capacity, frames are only ever accessed by index
+        self.frames = memory::reallocate(
+            unsafe { NonNull::new_unchecked(self.frames) },
+            old_capacity,
+            self.frames_capacity,
+        )
+        .as_ptr();
+    }
+
+    // the stack may move, so every pointer into it is relocated: stack_top, the frames' slots and open upvalues
+    // it does not grow past max_stack, a smaller limit only applies to calls
+    fn grow_stack(&mut self) -> miette::Result<()> {
+        let old_capacity = self.stack_capacity;
+        if old_capacity >= self.max_stack {
+            let labels = if self.frame_count > 0 {
+                vec![LabeledSpan::at(
+                    self.current_frame().current_location(),
+                    "here",
+                )]
+            } else {
+                vec![]
+            };
+            miette::bail!(labels = labels, "Stack overflow");
+        }
+        let old_stack = self.stack;
+        self.stack_capacity = memory::grow_capacity(old_capacity).min(self.max_stack);
+        // SAFETY: stack was allocated with old_capacity
+        self.stack = memory::reallocate(
+            unsafe { NonNull::new_unchecked(old_stack) },
+            old_capacity,
+            self.stack_capacity,
+        )
+        .as_ptr();
+        if self.stack == old_stack {
+            return Ok(());
+        }
+        // old pointers are dangling, only their addresses are used to compute the offset
+        let new_stack = self.stack;
+        let relocate = |pointer: *mut Value| unsafe {
+            new_stack.add((pointer as usize - old_stack as usize) / mem::size_of::<Value>())
+        };
+        self.stack_top = relocate(self.stack_top);
+        for i in 0..self.frame_count {
+            unsafe {
+                let frame = self.frames.add(i);
+                (*frame).slots = relocate((*frame).slots);
+            }
+        }
+        let mut upvalue = self.open_upvalues;
+        while let Some(mut current) = upvalue {
+            if let Obj::Upvalue { location, next, .. } = current.deref_mut() {
+                *location = relocate(*location);
+                upvalue = *next;
+            } else {
+                unreachable!()
+            }
+        }
+        Ok(())
+    }
+
+    fn stack_len(&self) -> usize {
+        // SAFETY: stack_top always points into the stack or one past its last value
+        unsafe { self.stack_top.offset_from(self.stack) as usize }
+    }
+
+    // grows the stack on demand, pointers into the stack are invalidated by a push
+    fn push(&mut self, value: Value) -> miette::Result<()> {
+        if self.stack_len() == self.stack_capacity {
+            self.grow_stack()?;
+        }
+        // SAFETY: the stack has room for at least one more value
+        unsafe {
+            *self.stack_top = value;
+            self.stack_top = self.stack_top.add(1);
+        }
+        Ok(())
     }
 
     fn pop(&mut self) -> Value {
-        // SAFETY: NOT SAFE, stack could overflow and underflow
+        // SAFETY: NOT SAFE, stack could underflow
         unsafe {
             self.stack_top = self.stack_top.sub(1);
             *self.stack_top
@@ -740,7 +1420,7 @@ impl VM {
     }
 
     fn peek(&self, distance: u8) -> Value {
-        // SAFETY: NOT SAFE, stack could overflow and underflow
+        // SAFETY: NOT SAFE, stack could underflow
         unsafe { *self.stack_top.sub(1 + distance as usize) }
     }
 
@@ -756,7 +1436,10 @@ impl VM {
     }
 
     fn reset_stack(&mut self) {
+        self.abandon_imports(0);
         self.stack_top = self.stack;
+        self.frame_count = 0;
+        self.open_upvalues = None;
     }
 
     fn stacktrace(&self) -> String {
@@ -772,6 +1455,9 @@ impl VM {
                 } else {
                     let _ = writeln!(trace, "script");
                 }
+                if (*frame).tail_call {
+                    let _ = writeln!(trace, "(...tail calls...)");
+                }
             }
         }
         trace
@@ -780,33 +1466,40 @@ impl VM {
 
 impl Drop for VM {
     fn drop(&mut self) {
-        //Safety: Layouts are same as in new.
+        self.reset_stack();
+        self.set_instruction_budget(None);
+        self.set_deadline(None);
+        self.gc.finalize_all();
+        if let Err(err) = self.run_finalizers() {
+            warn!("finalizers stopped: {}", err);
+        }
+        //Safety: both were allocated with their current capacity
         unsafe {
-            alloc::dealloc(
-                self.stack as *mut u8,
-                Layout::array::<Value>(STACK_MAX).unwrap(),
-            );
-            alloc::dealloc(
-                self.frames as *mut u8,
-                Layout::array::<Value>(FRAMES_MAX).unwrap(),
-            );
+            memory::free_array(NonNull::new_unchecked(self.stack), self.stack_capacity);
+            memory::free_array(NonNull::new_unchecked(self.frames), self.frames_capacity);
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use datadriven::walk;
     use miette::NamedSource;
     use serde_json::Value;
 
-    use crate::printer::{vec_printer::VecPrinter, Printer};
+    use crate::{
+        error::InterpreterError,
+        gc::snapshot::Root,
+        printer::{vec_printer::VecPrinter, Printer},
+    };
 
     use super::VM;
 
     impl VM {
         pub fn with_printer(printer: Box<dyn Printer>) -> Self {
-            let mut vm = VM::new();
+            let mut vm = VM::builder().build();
             vm.printer = printer;
             vm
         }
@@ -875,6 +1568,520 @@ closure();"#
         assert_eq!(printer.get_output(), "outside\n");
     }
 
+    #[test]
+    fn stacktrace_marks_tail_calls() {
+        let input = r#"
+fun fail() {
+  return nil + 1;
+}
+fun tail() {
+  return fail();
+}
+tail();"#
+            .to_string();
+        let mut vm = VM::with_printer(Box::new(VecPrinter::new()));
+        let err = vm
+            .interpret(NamedSource::new("miri_test", input))
+            .unwrap_err();
+        assert_matches!(err, InterpreterError::RuntimeError { stacktrace, .. } if stacktrace == "[line 3] in fail()\n(...tail calls...)\n[line 8] in script\n");
+    }
+
+    #[test]
+    fn inline_caches_hit_on_monomorphic_call_sites() {
+        let input = r#"
+class A {
+  init() { this.x = 1; }
+  get() { return this.x; }
+}
+var a = A();
+for (var i = 0; i < 10; i = i + 1) { a.get(); }"#
+            .to_string();
+        let mut vm = VM::with_printer(Box::new(VecPrinter::new()));
+        vm.interpret(NamedSource::new("miri_test", input)).unwrap();
+        let stats = vm.cache_stats();
+        // first invoke misses for the field and the method, first `this.x` and the SetProperty in init miss
+        assert_eq!(stats.misses, 4);
+        assert_eq!(stats.hits, 27);
+    }
+
+    #[test]
+    fn limits_are_configurable() {
+        let input = r#"
+fun depth(n) {
+  if (n == 0) return 0;
+  return 1 + depth(n - 1);
+}
+print depth(500);"#
+            .to_string();
+        let printer = VecPrinter::new();
+        let mut vm = VM::builder().max_frames(1000).build();
+        vm.printer = Box::new(printer.clone());
+        vm.interpret(NamedSource::new("miri_test", input.clone()))
+            .unwrap();
+        assert_eq!(vm.stack_top, vm.stack, "Stack is not empty");
+        assert_eq!(printer.get_output(), "500\n");
+
+        let mut vm = VM::builder().max_frames(1000).max_stack(100).build();
+        vm.printer = Box::new(VecPrinter::new());
+        let err = vm
+            .interpret(NamedSource::new("miri_test", input))
+            .unwrap_err();
+        assert_matches!(err, InterpreterError::RuntimeError { error, .. } if error.to_string() == "Stack overflow");
+        assert_eq!(vm.stack_top, vm.stack, "Stack is not empty");
+    }
+
+    #[test]
+    fn stack_does_not_grow_past_max_stack() {
+        // the call passes the check, the locals of the callee overflow the stack
+        let locals = |count| {
+            (0..count)
+                .map(|i| format!("  var v{i} = {i};\n"))
+                .collect::<String>()
+        };
+        let input = format!(
+            "fun inner() {{\n{}}}\nfun outer() {{\n{}  inner();\n}}\nouter();\nprint \"done\";",
+            locals(150),
+            locals(150)
+        );
+        let printer = VecPrinter::new();
+        let mut vm = VM::builder().build();
+        vm.printer = Box::new(printer.clone());
+        vm.interpret(NamedSource::new("miri_test", input.clone()))
+            .unwrap();
+        assert_eq!(printer.get_output(), "done\n");
+
+        let mut vm = VM::builder().max_stack(300).build();
+        vm.printer = Box::new(VecPrinter::new());
+        let err = vm
+            .interpret(NamedSource::new("miri_test", input))
+            .unwrap_err();
+        assert_matches!(err, InterpreterError::RuntimeError { error, .. } if error.to_string() == "Stack overflow");
+        assert!(vm.stack_capacity <= 300);
+        assert_eq!(vm.stack_top, vm.stack, "Stack is not empty");
+    }
+
+    #[test]
+    fn incremental_collection_keeps_reachable_objects() {
+        let input = r#"
+fun cons(value, next) {
+  fun get(first) {
+    if (first) return value;
+    return next;
+  }
+  return get;
+}
+var head = nil;
+for (var i = 0; i < 5000; i = i + 1) {
+  head = cons(i, head);
+  cons(i, nil);
+}
+var sum = 0;
+while (head != nil) {
+  sum = sum + head(true);
+  head = head(false);
+}
+print sum;"#
+            .to_string();
+        let printer = VecPrinter::new();
+        let mut vm = VM::builder().gc_slice_budget(1).build();
+        vm.printer = Box::new(printer.clone());
+        vm.interpret(NamedSource::new("miri_test", input)).unwrap();
+        assert_eq!(printer.get_output(), "12497500\n");
+    }
+
+    #[test]
+    fn out_of_memory_is_a_runtime_error() {
+        let input = r#"
+var s = "x";
+while (true) {
+  s = s + s;
+}"#
+        .to_string();
+        let printer = VecPrinter::new();
+        let mut vm = VM::builder().max_heap_size(Some(200_000)).build();
+        vm.printer = Box::new(printer.clone());
+        let err = vm
+            .interpret(NamedSource::new("miri_test", input))
+            .unwrap_err();
+        assert_matches!(err, InterpreterError::RuntimeError { error, stacktrace } if error.to_string() == "out of memory" && stacktrace == "[line 4] in script\n");
+        assert_eq!(vm.stack_top, vm.stack, "Stack is not empty");
+
+        let input = r#"
+class Node {}
+var head = nil;
+while (true) {
+  var node = Node();
+  node.next = head;
+  head = node;
+}"#
+        .to_string();
+        let err = vm
+            .interpret(NamedSource::new("miri_test", input))
+            .unwrap_err();
+        assert_matches!(err, InterpreterError::RuntimeError { error, .. } if error.to_string() == "out of memory");
+
+        // the vm is still usable, once the garbage is released
+        vm.interpret(NamedSource::new(
+            "miri_test",
+            "head = nil; print 1;".to_string(),
+        ))
+        .unwrap();
+        assert_eq!(printer.get_output(), "1\n");
+    }
+
+    #[test]
+    fn growing_buffers_respect_the_max_heap_size() {
+        // the parts are interned, the buffer of the list is what grows
+        let input = r#"
+var s = "a,";
+for (var i = 0; i < 14; i = i + 1) {
+  s = s + s;
+}
+var parts = s.split(",");"#
+            .to_string();
+        let mut vm = VM::builder().max_heap_size(Some(200_000)).build();
+        let err = vm
+            .interpret(NamedSource::new("miri_test", input))
+            .unwrap_err();
+        assert_matches!(err, InterpreterError::RuntimeError { error, stacktrace } if error.to_string() == "out of memory" && stacktrace == "[line 6] in script\n");
+        assert!(vm.gc.bytes_allocated() <= 200_000);
+
+        let input = r#"
+var s = "x";
+for (var i = 0; i < 18; i = i + 1) {
+  s = "${s}${s}";
+}"#
+        .to_string();
+        let err = vm
+            .interpret(NamedSource::new("miri_test", input))
+            .unwrap_err();
+        assert_matches!(err, InterpreterError::RuntimeError { error, .. } if error.to_string() == "out of memory");
+        assert!(vm.gc.bytes_allocated() <= 200_000);
+    }
+
+    #[test]
+    fn instruction_budget_is_resumable() {
+        let input = r#"
+var i = 0;
+while (i < 1000) {
+  i = i + 1;
+}
+print i;"#
+            .to_string();
+        let printer = VecPrinter::new();
+        let mut vm = VM::builder().max_instructions(Some(100)).build();
+        vm.printer = Box::new(printer.clone());
+        let mut slices = 1;
+        let mut result = vm.interpret(NamedSource::new("miri_test", input));
+        while let Err(InterpreterError::InstructionBudgetExhausted) = result {
+            slices += 1;
+            vm.set_instruction_budget(Some(100));
+            result = vm.resume();
+        }
+        result.unwrap();
+        assert!(slices > 10, "only {slices} slices");
+        assert_eq!(printer.get_output(), "1000\n");
+        assert_eq!(vm.stack_top, vm.stack, "Stack is not empty");
+    }
+
+    #[test]
+    fn exhausted_budgets_are_not_caught() {
+        let input = "try { while (true) {} } catch (e) { print e; }".to_string();
+        let printer = VecPrinter::new();
+        let mut vm = VM::builder().max_instructions(Some(100)).build();
+        vm.printer = Box::new(printer.clone());
+        let result = vm.interpret(NamedSource::new("miri_test", input));
+        assert_matches!(result, Err(InterpreterError::InstructionBudgetExhausted));
+        assert_eq!(printer.get_output(), "");
+    }
+
+    #[test]
+    fn deadline_stops_infinite_loops() {
+        let input = "fun f() {} while (true) { f(); }".to_string();
+        let mut vm = VM::builder()
+            .timeout(Some(Duration::from_millis(10)))
+            .build();
+        let err = vm
+            .interpret(NamedSource::new("miri_test", input))
+            .unwrap_err();
+        assert_matches!(err, InterpreterError::DeadlineExceeded);
+
+        // a new script abandons the stopped one
+        let printer = VecPrinter::new();
+        vm.printer = Box::new(printer.clone());
+        vm.interpret(NamedSource::new("miri_test", "print 1;".to_string()))
+            .unwrap();
+        assert_eq!(printer.get_output(), "1\n");
+        assert_eq!(vm.stack_top, vm.stack, "Stack is not empty");
+    }
+
+    #[test]
+    fn interrupt_aborts_running_script() {
+        let printer = VecPrinter::new();
+        let mut vm = VM::with_printer(Box::new(printer.clone()));
+        let interrupt = vm.interrupt_handle();
+        let interrupter = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            interrupt.interrupt();
+        });
+        let err = vm
+            .interpret(NamedSource::new("miri_test", "while (true) {}".to_string()))
+            .unwrap_err();
+        interrupter.join().unwrap();
+        assert_matches!(err, InterpreterError::RuntimeError { error, stacktrace } if error.to_string() == "interrupted" && stacktrace == "[line 1] in script\n");
+        assert_eq!(vm.stack_top, vm.stack, "Stack is not empty");
+
+        vm.interpret(NamedSource::new("miri_test", "print 1;".to_string()))
+            .unwrap();
+        assert_eq!(printer.get_output(), "1\n");
+    }
+
+    #[test]
+    fn heap_snapshot_lists_references_and_roots() {
+        let input = r#"
+class Leak {}
+var leak = Leak();
+leak.payload = "secret";
+"#
+        .to_string();
+        let mut vm = VM::builder().build();
+        vm.interpret(NamedSource::new("miri_test", input)).unwrap();
+        let snapshot = vm.heap_snapshot();
+        let instance = snapshot
+            .objects
+            .iter()
+            .find(|o| o.kind == "instance")
+            .unwrap();
+        assert_eq!(instance.root, Root::Global);
+        assert_eq!(instance.description, "Leak instance");
+        let payload = snapshot
+            .objects
+            .iter()
+            .find(|o| o.description == "secret")
+            .unwrap();
+        assert!(instance.references.contains(&payload.id));
+        assert!(instance.retained_size >= instance.size + payload.size);
+
+        let json: Value = serde_json::from_str(&snapshot.to_json()).unwrap();
+        let objects = json["objects"].as_array().unwrap();
+        assert_eq!(objects.len(), snapshot.objects.len());
+        assert_eq!(objects[instance.id]["kind"], "instance");
+        assert_eq!(objects[instance.id]["root"], "global");
+    }
+
+    #[test]
+    fn heap_snapshot_native_returns_json() {
+        let printer = VecPrinter::new();
+        let mut vm = VM::with_printer(Box::new(printer.clone()));
+        vm.interpret(NamedSource::new(
+            "miri_test",
+            "print heap_snapshot();".to_string(),
+        ))
+        .unwrap();
+        let json: Value = serde_json::from_str(&printer.get_output()).unwrap();
+        assert!(!json["objects"].as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn finalizers_may_grow_the_stack() {
+        let input = r#"
+fun deep(n) {
+  if (n == 0) return 0;
+  return 1 + deep(n - 1);
+}
+class Resource {
+  finalize() { print deep(900); }
+}
+fun main() {
+  var a = 1;
+  var b = 2;
+  var c = 3;
+  Resource();
+  gc();
+  deep(0);
+  print a + b + c;
+}
+main();
+"#
+        .to_string();
+        let printer = VecPrinter::new();
+        let mut vm = VM::builder().max_frames(2000).build();
+        vm.printer = Box::new(printer.clone());
+        vm.interpret(NamedSource::new("miri_test", input)).unwrap();
+        assert_eq!(printer.get_output(), "900\n6\n");
+        assert_eq!(vm.stack_top, vm.stack, "Stack is not empty");
+    }
+
+    #[test]
+    fn exhausted_budget_in_a_finalizer_stops_the_script() {
+        let input = r#"
+class Resource {
+  finalize() { while (true) {} }
+}
+fun main() {
+  var a = 1;
+  Resource();
+  gc();
+  print a;
+  print a + 1;
+}
+main();
+"#
+        .to_string();
+        let printer = VecPrinter::new();
+        let mut vm = VM::builder().max_instructions(Some(1000)).build();
+        vm.printer = Box::new(printer.clone());
+        let err = vm
+            .interpret(NamedSource::new("miri_test", input))
+            .unwrap_err();
+        assert_matches!(err, InterpreterError::InstructionBudgetExhausted);
+        vm.set_instruction_budget(Some(1000));
+        vm.resume().unwrap();
+        assert_eq!(printer.get_output(), "1\n2\n");
+        assert_eq!(vm.stack_top, vm.stack, "Stack is not empty");
+    }
+
+    #[test]
+    fn globals_defined_by_later_interpret_calls_survive_collections() {
+        let printer = VecPrinter::new();
+        let mut vm = VM::with_printer(Box::new(printer.clone()));
+        for line in [
+            "class A {}",
+            "for (var i = 0; i < 100000; i = i + 1) A();",
+            "var fresh123 = 1;",
+            "for (var i = 0; i < 100000; i = i + 1) A();",
+            "print fresh123;",
+        ] {
+            vm.interpret(NamedSource::new("miri_test", line.to_string()))
+                .unwrap();
+        }
+        assert_eq!(printer.get_output(), "1\n");
+    }
+
+    #[test]
+    fn static_fields_set_by_later_interpret_calls_survive_collections() {
+        let printer = VecPrinter::new();
+        let mut vm = VM::with_printer(Box::new(printer.clone()));
+        for line in [
+            "class A {}",
+            "for (var i = 0; i < 100000; i = i + 1) A();",
+            "A.fresh456 = 1;",
+            "for (var i = 0; i < 100000; i = i + 1) A();",
+            "print A.fresh456;",
+        ] {
+            vm.interpret(NamedSource::new("miri_test", line.to_string()))
+                .unwrap();
+        }
+        assert_eq!(printer.get_output(), "1\n");
+    }
+
+    #[test]
+    fn each_vm_accounts_its_own_heap() {
+        let mut first = VM::builder().build();
+        let before = first.gc_stats().bytes_allocated;
+        let mut second = VM::builder().build();
+        let input = r#"
+class A {}
+var a = A();
+for (var i = 0; i < 1000; i = i + 1) {
+  a.field = "field" + "${i}";
+}
+var parts = "a,b,c,d,e,f,g,h,i,j".split(",");
+"#
+        .to_string();
+        second
+            .interpret(NamedSource::new("miri_test", input))
+            .unwrap();
+        assert!(second.gc_stats().bytes_allocated > before);
+        assert_eq!(first.gc_stats().bytes_allocated, before);
+    }
+
+    #[test]
+    fn remaining_finalizers_run_when_the_vm_is_dropped() {
+        let input = r#"
+class Resource {
+  finalize() { print "finalize"; }
+}
+var kept = Resource();
+"#
+        .to_string();
+        let printer = VecPrinter::new();
+        let mut vm = VM::with_printer(Box::new(printer.clone()));
+        vm.interpret(NamedSource::new("miri_test", input)).unwrap();
+        assert_eq!(printer.get_output(), "");
+        drop(vm);
+        assert_eq!(printer.get_output(), "finalize\n");
+    }
+
+    #[test]
+    fn drop_hooks_of_foreign_objects_run_when_collected() {
+        use std::cell::Cell;
+
+        use crate::types::{foreign::Foreign, obj::Obj, value::Value};
+
+        thread_local! {
+            static DROPPED: Cell<usize> = const { Cell::new(0) };
+        }
+        let printer = VecPrinter::new();
+        let mut vm = VM::with_printer(Box::new(printer.clone()));
+        vm.define_native("resource", |_, _, vm| {
+            let foreign = Foreign::new("resource", 42usize)
+                .on_drop(|data: usize| DROPPED.with(|dropped| dropped.set(data)));
+            Ok(Value::Obj(vm.alloc(Obj::Foreign(foreign))?))
+        });
+        let input = r#"
+var r = resource();
+print r;
+gc();
+r = nil;
+gc();
+"#
+        .to_string();
+        vm.interpret(NamedSource::new("miri_test", input)).unwrap();
+        assert_eq!(printer.get_output(), "<resource>\n");
+        assert_eq!(DROPPED.with(Cell::get), 42);
+    }
+
+    #[test]
+    fn file_natives_are_not_defined_by_default() {
+        let mut vm = VM::builder().build();
+        let err = vm
+            .interpret(NamedSource::new(
+                "miri_test",
+                r#"open("file.txt", "w");"#.to_string(),
+            ))
+            .unwrap_err();
+        assert_matches!(err, InterpreterError::RuntimeError { error, .. } if error.to_string() == "Undefined variable open");
+    }
+
+    #[test]
+    fn foreign_files_are_flushed_when_collected() {
+        let path = std::env::temp_dir().join(format!("lox_foreign_{}.txt", std::process::id()));
+        let input = format!(
+            r#"
+var file = open("{path}", "w");
+write(file, "written");
+file = nil;
+gc();
+file = open("{path}", "r");
+print read_line(file);
+print read_line(file);
+close(file);
+read_line(file);
+"#,
+            path = path.display()
+        );
+        let printer = VecPrinter::new();
+        let mut vm = VM::builder().file_access(true).build();
+        vm.printer = Box::new(printer.clone());
+        let err = vm
+            .interpret(NamedSource::new("miri_test", input))
+            .unwrap_err();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(printer.get_output(), "written\nNil\n");
+        assert_matches!(err, InterpreterError::RuntimeError { error, .. } if error.to_string() == "file is closed");
+    }
+
     fn format_json(json: String) -> String {
         let x: Value = serde_json::from_str(json.as_str()).unwrap();
         serde_json::to_string_pretty(&x).unwrap()
@@ -0,0 +1,123 @@
+use std::{fs, ops::Deref, path::Path};
+
+use miette::{LabeledSpan, NamedSource};
+
+use crate::{
+    error::ImportError,
+    parser::Parser,
+    types::{
+        closure::Closure, module::Module, obj::Obj, obj_ref::ObjRef, string::LoxString,
+        value::Value,
+    },
+};
+
+use super::VM;
+
+impl VM {
+    /// Pushes the module at the path relative to the current script. A module is run once when it
+    /// is first imported, it returns itself from slot zero so the import continues without nesting.
+    pub(super) fn import(&mut self, index: u8) -> miette::Result<()> {
+        let path = self.current_frame().chunk().constants[index as usize];
+        let importer = self.current_frame().chunk().source.name().to_string();
+        let resolved = Path::new(&importer)
+            .parent()
+            .unwrap_or(Path::new(""))
+            .join(&path.as_string().string);
+        let canonical = fs::canonicalize(&resolved)
+            .and_then(|canonical| Ok((fs::read_to_string(&canonical)?, canonical)));
+        let (contents, canonical) = match canonical {
+            Ok(read) => read,
+            Err(err) => miette::bail!(
+                labels = vec![LabeledSpan::at(
+                    self.current_frame().current_location(),
+                    "here"
+                )],
+                "Unable to import {}: {}",
+                resolved.display(),
+                err
+            ),
+        };
+
+        let key = Value::Obj(self.alloc(canonical.display().to_string())?);
+        if let Some(module) = self.modules.get(key) {
+            if self.is_importing(*module.as_obj()) {
+                miette::bail!(
+                    labels = vec![LabeledSpan::at(
+                        self.current_frame().current_location(),
+                        "here"
+                    )],
+                    "Import cycle, {} is imported while it is run",
+                    resolved.display()
+                );
+            }
+            self.push(module)?;
+            return Ok(());
+        }
+
+        let name = resolved.display().to_string();
+        let src = NamedSource::new(name.clone(), contents);
+        let function = match Parser::compile(&src, &mut self.gc) {
+            Ok(function) => self.gc.alloc(function), // gc.alloc to prevent collection
+            Err(err) => {
+                return Err(ImportError {
+                    path: name,
+                    location: self.current_frame().current_location(),
+                    errors: vec![err.with_source_code(src)],
+                }
+                .into())
+            }
+        };
+        self.sources.push(src);
+        self.push(Value::Obj(function))?;
+        self.push(key)?;
+        let module = self.alloc(Obj::Module(Module::new(LoxString::string(name), key)))?;
+        self.pop();
+        self.modules.insert(key, Value::Obj(module));
+        let closure = self.alloc(Obj::Closure(Closure::new(function, vec![], module)))?;
+        self.pop();
+        self.push(Value::Obj(module))?;
+        self.call(0, closure, closure.as_closure())
+    }
+
+    pub(super) fn module_variable(
+        &mut self,
+        module: &Module,
+        name: Value,
+    ) -> miette::Result<Value> {
+        if let Some(value) = module.globals.get(name) {
+            Ok(value)
+        } else {
+            miette::bail!(
+                labels = vec![LabeledSpan::at(
+                    self.current_frame().current_location(),
+                    "here"
+                )],
+                "Undefined variable {} in {}",
+                name,
+                module
+            )
+        }
+    }
+
+    // the script of the module has a frame, so it did not finish yet
+    fn is_importing(&self, module: ObjRef) -> bool {
+        (0..self.frame_count).any(|i| {
+            let frame = unsafe { &*self.frames.add(i) };
+            frame.module() == module && frame.function().name().is_none()
+        })
+    }
+
+    /// Forgets the modules whose scripts are run by the frames from `frame_index` on,
+    /// so the next import runs them again instead of using partly defined globals.
+    pub(super) fn abandon_imports(&mut self, frame_index: usize) {
+        for i in frame_index..self.frame_count {
+            let frame = unsafe { &*self.frames.add(i) };
+            if frame.function().name().is_none() {
+                let path = frame.module().deref().as_module().path();
+                if path != Value::Nil {
+                    self.modules.delete(path);
+                }
+            }
+        }
+    }
+}
@@ -1,7 +1,28 @@
-use crate::types::{obj::Obj, value::Value};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    ops::{Deref, DerefMut},
+};
+
+use miette::LabeledSpan;
+use tracing::warn;
+
+use crate::{
+    datastructures::hash_table::HashTable,
+    types::{
+        class::Class, foreign::Foreign, instance::Instance, obj::Obj, obj_ref::ObjRef,
+        string::LoxString, value::Value,
+    },
+};
 
 use super::VM;
 
+// the data of the foreign objects returned by open
+enum LoxFile {
+    Reader(BufReader<File>),
+    Writer(BufWriter<File>),
+}
+
 impl VM {
     pub(super) fn define_native_functions(&mut self) {
         self.define_native("clock", |_, _, _| {
@@ -9,25 +30,249 @@ impl VM {
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap()
                 .as_secs_f64();
-            Value::Number(millis)
+            Ok(Value::Number(millis))
         });
         self.define_native("heapdump", |_, _, vm| {
             vm.heapdump();
-            Value::Nil
+            Ok(Value::Nil)
         });
         self.define_native("gc", |_, _, vm| {
             vm.collect_garbage();
-            Value::Nil
+            Ok(Value::Nil)
+        });
+        self.define_native("gc_stats", |_, _, vm| vm.gc_stats_instance());
+        // the snapshot as a json string, writing it somewhere is up to the host
+        self.define_native("heap_snapshot", |_, _, vm| {
+            let json = vm.heap_snapshot().to_json();
+            Ok(Value::Obj(vm.alloc(json)?))
+        });
+        self.define_native("weak_ref", |arg_count, args, vm| {
+            if arg_count != 1 {
+                return Err(vm.native_error("weak_ref expects a target"));
+            }
+            let target = unsafe { *args };
+            Ok(Value::Obj(vm.alloc(Obj::WeakRef(target))?))
+        });
+        self.define_native("weak_ref_get", |arg_count, args, vm| {
+            match vm.weak_arg(arg_count, args, 1)?.deref() {
+                Obj::WeakRef(target) => Ok(*target),
+                _ => Err(vm.native_error("weak_ref_get expects a weak ref")),
+            }
+        });
+        self.define_native("weak_map", |_, _, vm| {
+            Ok(Value::Obj(vm.alloc(Obj::WeakMap(HashTable::new()))?))
+        });
+        // values are not written through a barrier, mark_ephemerons visits all weak maps at the end of marking
+        self.define_native("weak_map_set", |arg_count, args, vm| {
+            match vm.weak_arg(arg_count, args, 3)?.deref_mut() {
+                Obj::WeakMap(table) => unsafe {
+                    vm.reserve(table.growth())?;
+                    let before = table.allocated_bytes();
+                    table.insert(*args.add(1), *args.add(2));
+                    vm.gc.charge(before, table.allocated_bytes());
+                    Ok(Value::Nil)
+                },
+                _ => Err(vm.native_error("weak_map_set expects a weak map")),
+            }
+        });
+        self.define_native("weak_map_get", |arg_count, args, vm| {
+            match vm.weak_arg(arg_count, args, 2)?.deref() {
+                Obj::WeakMap(table) => Ok(table.get(unsafe { *args.add(1) }).unwrap_or(Value::Nil)),
+                _ => Err(vm.native_error("weak_map_get expects a weak map")),
+            }
+        });
+        self.define_native("weak_map_delete", |arg_count, args, vm| {
+            match vm.weak_arg(arg_count, args, 2)?.deref_mut() {
+                Obj::WeakMap(table) => Ok(Value::Boolean(table.delete(unsafe { *args.add(1) }))),
+                _ => Err(vm.native_error("weak_map_delete expects a weak map")),
+            }
+        });
+    }
+
+    // GcStats instance with the fields bytes_allocated, next_gc and <kind>_count and <kind>_bytes per object kind
+    fn gc_stats_instance(&mut self) -> miette::Result<Value> {
+        let stats = self.gc_stats();
+        let mut fields = vec![
+            ("bytes_allocated".to_owned(), stats.bytes_allocated),
+            ("next_gc".to_owned(), stats.next_gc),
+        ];
+        for (kind, obj_stats) in stats.objects {
+            fields.push((format!("{kind}_count"), obj_stats.count));
+            fields.push((format!("{kind}_bytes"), obj_stats.bytes));
+        }
+
+        let class = self.alloc(Obj::Class(Class::new(LoxString::from_str("GcStats"))))?;
+        self.push(Value::Obj(class))?;
+        let mut instance = self.alloc(Obj::Instance(Instance::new(class, self.empty_shape)))?;
+        self.push(Value::Obj(instance))?;
+        for (name, value) in fields {
+            let name = self.alloc(name)?;
+            self.push(Value::Obj(name))?;
+            let shape = instance.as_instance().shape();
+            let next = self.shape_transition(shape, Value::Obj(name))?;
+            self.reserve(instance.as_instance().field_growth())?;
+            let before = instance.as_instance().allocated_bytes();
+            instance
+                .as_instance_mut()
+                .add_field(next, Value::Number(value as f64));
+            self.gc
+                .charge(before, instance.as_instance().allocated_bytes());
+            self.gc.write_barrier(instance, Value::Obj(next));
+            self.pop();
+        }
+        self.pop();
+        self.pop();
+        Ok(Value::Obj(instance))
+    }
+
+    pub(super) fn define_file_natives(&mut self) {
+        self.define_native("open", |arg_count, args, vm| {
+            if arg_count != 2 {
+                return Err(vm.native_error("open expects a path and a mode"));
+            }
+            let (path, mode) = match unsafe { (*args, *args.add(1)) } {
+                (Value::Obj(path), Value::Obj(mode))
+                    if matches!(*path, Obj::String(_)) && matches!(*mode, Obj::String(_)) =>
+                {
+                    (
+                        path.as_string().string.clone(),
+                        mode.as_string().string.clone(),
+                    )
+                }
+                _ => return Err(vm.native_error("open expects a path and a mode")),
+            };
+            let file = match mode.as_str() {
+                "r" => File::open(&path).map(|file| LoxFile::Reader(BufReader::new(file))),
+                "w" => File::create(&path).map(|file| LoxFile::Writer(BufWriter::new(file))),
+                _ => return Err(vm.native_error(format!("unknown file mode {mode}"))),
+            }
+            .map_err(|err| vm.native_error(format!("unable to open {path}: {err}")))?;
+            let foreign = Foreign::new("file", file).on_drop(|file: LoxFile| {
+                if let LoxFile::Writer(mut writer) = file {
+                    if let Err(err) = writer.flush() {
+                        warn!("unable to flush file: {err}");
+                    }
+                }
+            });
+            Ok(Value::Obj(vm.alloc(Obj::Foreign(foreign))?))
+        });
+        // returns the line without its line break or nil at the end of the file
+        self.define_native("read_line", |arg_count, args, vm| {
+            let mut line = String::new();
+            let read = match vm.file_arg(arg_count, args, 1)? {
+                LoxFile::Reader(reader) => reader.read_line(&mut line),
+                LoxFile::Writer(_) => return Err(vm.native_error("file is not open for reading")),
+            }
+            .map_err(|err| vm.native_error(format!("unable to read file: {err}")))?;
+            if read == 0 {
+                return Ok(Value::Nil);
+            }
+            if line.ends_with('\n') {
+                line.pop();
+            }
+            Ok(Value::Obj(vm.alloc(line)?))
+        });
+        self.define_native("write", |arg_count, args, vm| {
+            let file = vm.file_arg(arg_count, args, 2)?;
+            let value = unsafe { *args.add(1) }.to_string();
+            match file {
+                LoxFile::Writer(writer) => writer.write_all(value.as_bytes()),
+                LoxFile::Reader(_) => return Err(vm.native_error("file is not open for writing")),
+            }
+            .map_err(|err| vm.native_error(format!("unable to write file: {err}")))?;
+            Ok(Value::Nil)
+        });
+        // closes the file without waiting for the collector
+        self.define_native("close", |arg_count, args, vm| {
+            vm.file_arg(arg_count, args, 1)?;
+            let mut file = unsafe { *args };
+            if let Obj::Foreign(foreign) = file.as_obj_mut().deref_mut() {
+                if let Some(LoxFile::Writer(mut writer)) = foreign.take::<LoxFile>() {
+                    writer
+                        .flush()
+                        .map_err(|err| vm.native_error(format!("unable to write file: {err}")))?;
+                }
+            }
+            Ok(Value::Nil)
         });
     }
 
-    fn define_native(&mut self, name: &str, function: fn(u8, *mut Value, &mut VM) -> Value) {
-        let name = self.alloc(name);
-        self.push(Value::Obj(name));
-        let function = self.alloc(Obj::Native(function));
-        self.push(Value::Obj(function));
-        self.globals.insert(self.peek(1), self.peek(0));
+    fn file_arg(
+        &mut self,
+        arg_count: u8,
+        args: *mut Value,
+        arity: u8,
+    ) -> miette::Result<&mut LoxFile> {
+        if arg_count != arity {
+            return Err(self.native_error(format!(
+                "Expected {} arguments but got {}.",
+                arity, arg_count
+            )));
+        }
+        match unsafe { &mut *args } {
+            Value::Obj(obj) => match obj.deref_mut() {
+                Obj::Foreign(foreign) => match foreign.downcast_mut::<LoxFile>() {
+                    Some(file) => Ok(file),
+                    None => Err(self.native_error("file is closed")),
+                },
+                _ => Err(self.native_error("Expected a file.")),
+            },
+            _ => Err(self.native_error("Expected a file.")),
+        }
+    }
+
+    pub(super) fn native_error(&mut self, message: impl Into<String>) -> miette::Report {
+        miette::miette!(
+            labels = vec![LabeledSpan::at(
+                self.current_frame().current_location(),
+                "here"
+            )],
+            "{}",
+            message.into()
+        )
+    }
+
+    // the first argument of the weak ref and weak map natives
+    fn weak_arg(&mut self, arg_count: u8, args: *mut Value, arity: u8) -> miette::Result<ObjRef> {
+        if arg_count != arity {
+            return Err(self.native_error(format!(
+                "Expected {} arguments but got {}.",
+                arity, arg_count
+            )));
+        }
+        match unsafe { *args } {
+            Value::Obj(obj) => Ok(obj),
+            _ => Err(self.native_error("Expected a weak ref or weak map.")),
+        }
+    }
+
+    pub(super) fn define_native(
+        &mut self,
+        name: &str,
+        function: fn(u8, *mut Value, &mut VM) -> miette::Result<Value>,
+    ) {
+        let (name, function) = self.alloc_native(name, function);
+        self.builtins.insert(name, function);
         self.pop();
         self.pop();
     }
+
+    // both stay on the stack until the caller stored them
+    pub(super) fn alloc_native(
+        &mut self,
+        name: &str,
+        function: fn(u8, *mut Value, &mut VM) -> miette::Result<Value>,
+    ) -> (Value, Value) {
+        let name = self
+            .alloc(name)
+            .expect("natives are defined before the heap is limited");
+        self.push(Value::Obj(name))
+            .expect("natives are defined on a short stack");
+        let function = self
+            .alloc(Obj::Native(function))
+            .expect("natives are defined before the heap is limited");
+        self.push(Value::Obj(function))
+            .expect("natives are defined on a short stack");
+        (self.peek(1), self.peek(0))
+    }
 }
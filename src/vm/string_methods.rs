@@ -0,0 +1,246 @@
+use crate::{
+    datastructures::vector::LoxVector,
+    gc::Node,
+    types::{obj::Obj, obj_ref::ObjRef, value::Value},
+};
+
+use super::VM;
+
+type Native = fn(u8, *mut Value, &mut VM) -> miette::Result<Value>;
+
+// methods are natives which find their receiver in the slot before the arguments,
+// lengths and indices count chars, not bytes
+impl VM {
+    pub(super) fn define_string_methods(&mut self) {
+        self.define_string_method("len", |arg_count, args, vm| {
+            vm.method_arity(arg_count, 0)?;
+            let length = receiver(args).as_string().string.chars().count();
+            Ok(Value::Number(length as f64))
+        });
+        self.define_string_method("substring", |arg_count, args, vm| {
+            vm.method_arity(arg_count, 2)?;
+            let start = vm.index_arg(unsafe { *args })?;
+            let end = vm.index_arg(unsafe { *args.add(1) })?;
+            let receiver = receiver(args);
+            let string = &receiver.as_string().string;
+            if start > end || end > string.chars().count() {
+                return Err(vm.native_error(format!(
+                    "Substring {start}..{end} is out of bounds of a string of length {}",
+                    string.chars().count()
+                )));
+            }
+            let substring: String = string.chars().skip(start).take(end - start).collect();
+            Ok(Value::Obj(vm.alloc(substring)?))
+        });
+        self.define_string_method("indexOf", |arg_count, args, vm| {
+            vm.method_arity(arg_count, 1)?;
+            let needle = vm.string_arg(args, 0)?;
+            let receiver = receiver(args);
+            let string = &receiver.as_string().string;
+            let index = match string.find(needle.as_string().string.as_str()) {
+                Some(byte_index) => string[..byte_index].chars().count() as f64,
+                None => -1.0,
+            };
+            Ok(Value::Number(index))
+        });
+        self.define_string_method("split", |arg_count, args, vm| {
+            vm.method_arity(arg_count, 1)?;
+            let separator = vm.string_arg(args, 0)?;
+            let receiver = receiver(args);
+            let string = &receiver.as_string().string;
+            let separator = separator.as_string().string.as_str();
+            // an empty separator splits into the chars
+            let parts: Vec<String> = if separator.is_empty() {
+                string.chars().map(String::from).collect()
+            } else {
+                string.split(separator).map(String::from).collect()
+            };
+            let mut list = vm.alloc(Obj::List(LoxVector::new()))?;
+            vm.push(Value::Obj(list))?;
+            for part in parts {
+                let part = Value::Obj(vm.alloc(part)?);
+                vm.push(part)?;
+                vm.reserve(list.as_list().growth())?;
+                vm.pop();
+                let before = list.as_list().allocated_bytes();
+                list.as_list_mut().push(part);
+                vm.gc.charge(before, list.as_list().allocated_bytes());
+                vm.gc.write_barrier(list, part);
+            }
+            Ok(vm.pop())
+        });
+        self.define_string_method("trim", |arg_count, args, vm| {
+            vm.method_arity(arg_count, 0)?;
+            let trimmed = receiver(args).as_string().string.trim().to_string();
+            Ok(Value::Obj(vm.alloc(trimmed)?))
+        });
+        self.define_string_method("upper", |arg_count, args, vm| {
+            vm.method_arity(arg_count, 0)?;
+            let upper = receiver(args).as_string().string.to_uppercase();
+            Ok(Value::Obj(vm.alloc(upper)?))
+        });
+        self.define_string_method("lower", |arg_count, args, vm| {
+            vm.method_arity(arg_count, 0)?;
+            let lower = receiver(args).as_string().string.to_lowercase();
+            Ok(Value::Obj(vm.alloc(lower)?))
+        });
+        self.define_string_method("replace", |arg_count, args, vm| {
+            vm.method_arity(arg_count, 2)?;
+            let from = vm.string_arg(args, 0)?;
+            let to = vm.string_arg(args, 1)?;
+            let receiver = receiver(args);
+            let string = &receiver.as_string().string;
+            let (from, to) = (&from.as_string().string, &to.as_string().string);
+            let matches = string.matches(from.as_str()).count();
+            vm.reserve(
+                size_of::<Node>() + string.len() - matches * from.len() + matches * to.len(),
+            )?;
+            let replaced = string.replace(from, to);
+            Ok(Value::Obj(vm.alloc(replaced)?))
+        });
+        self.define_string_method("startsWith", |arg_count, args, vm| {
+            vm.method_arity(arg_count, 1)?;
+            let prefix = vm.string_arg(args, 0)?;
+            let receiver = receiver(args);
+            let string = &receiver.as_string().string;
+            Ok(Value::Boolean(
+                string.starts_with(prefix.as_string().string.as_str()),
+            ))
+        });
+        self.define_string_method("contains", |arg_count, args, vm| {
+            vm.method_arity(arg_count, 1)?;
+            let needle = vm.string_arg(args, 0)?;
+            let receiver = receiver(args);
+            let string = &receiver.as_string().string;
+            Ok(Value::Boolean(
+                string.contains(needle.as_string().string.as_str()),
+            ))
+        });
+
+        self.define_list_method("len", |arg_count, args, vm| {
+            vm.method_arity(arg_count, 0)?;
+            Ok(Value::Number(receiver(args).as_list().len() as f64))
+        });
+    }
+
+    /// The method of a string or list receiver, none for other receivers.
+    pub(super) fn builtin_method(
+        &mut self,
+        receiver: ObjRef,
+        name: Value,
+    ) -> miette::Result<Option<Value>> {
+        let methods = match &*receiver {
+            Obj::String(_) => &self.string_methods,
+            Obj::List(_) => &self.list_methods,
+            _ => return Ok(None),
+        };
+        match methods.get(name) {
+            Some(method) => Ok(Some(method)),
+            None => {
+                Err(self.native_error(format!("Undefined method {name} on {}", receiver.kind())))
+            }
+        }
+    }
+
+    /// `string[index]` is the char at index as a string, `list[index]` the item.
+    pub(super) fn index(&mut self) -> miette::Result<()> {
+        let index = self.index_arg(self.peek(0))?;
+        let value = match self.peek(1) {
+            Value::Obj(obj) => match &*obj {
+                Obj::String(string) => match string.string.chars().nth(index) {
+                    Some(char) => Some(Value::Obj(self.alloc(char.to_string())?)),
+                    None => None,
+                },
+                Obj::List(items) => items.get(index).copied(),
+                _ => return Err(self.index_error()),
+            },
+            _ => return Err(self.index_error()),
+        };
+        let Some(value) = value else {
+            return Err(self.native_error(format!(
+                "Index {index} is out of bounds of {}",
+                self.peek(1)
+            )));
+        };
+        self.pop();
+        self.pop();
+        self.push(value)?;
+        Ok(())
+    }
+
+    /// `list[index] = value`, strings can not be changed.
+    pub(super) fn set_index(&mut self) -> miette::Result<()> {
+        let index = self.index_arg(self.peek(1))?;
+        let value = self.peek(0);
+        let mut list = match self.peek(2) {
+            Value::Obj(obj) if matches!(*obj, Obj::List(_)) => obj,
+            receiver => {
+                return Err(
+                    self.native_error(format!("Can only assign to items of lists, not {receiver}"))
+                )
+            }
+        };
+        let Some(item) = list.as_list_mut().get_mut(index) else {
+            return Err(self.native_error(format!(
+                "Index {index} is out of bounds of {}",
+                self.peek(2)
+            )));
+        };
+        *item = value;
+        self.gc.write_barrier(list, value);
+        self.pop();
+        self.pop();
+        self.pop();
+        self.push(value)?;
+        Ok(())
+    }
+
+    fn index_error(&mut self) -> miette::Report {
+        let message = format!("Can only index strings and lists, not {}", self.peek(1));
+        self.native_error(message)
+    }
+
+    fn define_string_method(&mut self, name: &str, function: Native) {
+        let (name, function) = self.alloc_native(name, function);
+        self.string_methods.insert(name, function);
+        self.pop();
+        self.pop();
+    }
+
+    fn define_list_method(&mut self, name: &str, function: Native) {
+        let (name, function) = self.alloc_native(name, function);
+        self.list_methods.insert(name, function);
+        self.pop();
+        self.pop();
+    }
+
+    fn method_arity(&mut self, arg_count: u8, arity: u8) -> miette::Result<()> {
+        if arg_count != arity {
+            return Err(self.native_error(format!(
+                "Expected {} arguments but got {}.",
+                arity, arg_count
+            )));
+        }
+        Ok(())
+    }
+
+    fn string_arg(&mut self, args: *mut Value, index: usize) -> miette::Result<ObjRef> {
+        match unsafe { *args.add(index) } {
+            Value::Obj(obj) if matches!(*obj, Obj::String(_)) => Ok(obj),
+            value => Err(self.native_error(format!("Expected a string but got {value}"))),
+        }
+    }
+
+    fn index_arg(&mut self, value: Value) -> miette::Result<usize> {
+        match value {
+            Value::Number(n) if n >= 0.0 && n.fract() == 0.0 => Ok(n as usize),
+            value => Err(self.native_error(format!(
+                "Expected a non negative integer index but got {value}"
+            ))),
+        }
+    }
+}
+
+fn receiver(args: *mut Value) -> ObjRef {
+    *unsafe { *args.sub(1) }.as_obj()
+}